@@ -0,0 +1,96 @@
+// Effect of the buffer sizes of `IndexBuilder` and `SeekableZLibReader` on
+// building an index, small random reads and sequential reads.
+
+use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use zran_rs::reader::SeekableZLibReader;
+use zran_rs::writer::SeekableGzWriter;
+use zran_rs::zran::IndexBuilder;
+
+const DATA_SIZE: usize = 16 << 20;
+const SPAN: u64 = 1 << 20;
+const SIZES: [usize; 4] = [4 << 10, 16 << 10, 256 << 10, 4 << 20];
+
+// Compressible text with some variety, and its gzip compressed form
+fn create_data() -> (Vec<u8>, Vec<u8>) {
+    let mut data = Vec::with_capacity(DATA_SIZE);
+    let mut state = 0x2545f4914f6cdd1du64;
+    while data.len() < DATA_SIZE {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        writeln!(data, "record {} value {:x}", data.len(), state % 100_000).unwrap();
+    }
+
+    let mut writer = SeekableGzWriter::new(vec![], SPAN).unwrap();
+    writer.write_all(&data).unwrap();
+    let (compressed, _) = writer.finish().unwrap();
+    (data, compressed)
+}
+
+fn bench_build(c: &mut Criterion) {
+    let (data, compressed) = create_data();
+    let mut group = c.benchmark_group("build_index");
+    group.throughput(Throughput::Bytes(data.len() as u64));
+    group.sample_size(10);
+    for size in SIZES {
+        group.bench_with_input(BenchmarkId::new("input", size), &size, |b, &size| {
+            let builder = IndexBuilder::new().span(SPAN).input_buffer_size(size);
+            b.iter(|| builder.build(&mut Cursor::new(&compressed)).unwrap());
+        });
+    }
+    group.finish();
+}
+
+fn bench_random_reads(c: &mut Criterion) {
+    let (data, compressed) = create_data();
+    let index = IndexBuilder::new()
+        .span(SPAN)
+        .build(&mut Cursor::new(&compressed))
+        .unwrap();
+
+    let mut group = c.benchmark_group("random_read_64");
+    for size in [256, 4 << 10, 16 << 10, 256 << 10] {
+        group.bench_with_input(BenchmarkId::new("output", size), &size, |b, &size| {
+            let mut reader = SeekableZLibReader::from_source(&compressed, index.clone())
+                .with_output_buffer_size(size);
+            let mut buffer = [0; 64];
+            let mut offset = 0;
+            b.iter(|| {
+                offset = (offset + 7_654_321) % (data.len() - 64);
+                reader.seek(SeekFrom::Start(offset as u64)).unwrap();
+                reader.read_exact(&mut buffer).unwrap();
+            });
+        });
+    }
+    group.finish();
+}
+
+fn bench_sequential(c: &mut Criterion) {
+    let (data, compressed) = create_data();
+    let index = IndexBuilder::new()
+        .span(SPAN)
+        .build(&mut Cursor::new(&compressed))
+        .unwrap();
+
+    let mut group = c.benchmark_group("sequential_read");
+    group.throughput(Throughput::Bytes(data.len() as u64));
+    group.sample_size(10);
+    for size in SIZES {
+        group.bench_with_input(BenchmarkId::new("read_ahead", size), &size, |b, &size| {
+            b.iter(|| {
+                let mut reader = SeekableZLibReader::from_source(&compressed, index.clone())
+                    .with_input_buffer_size(size)
+                    .with_read_ahead(size);
+                let mut out = Vec::with_capacity(data.len());
+                reader.read_to_end(&mut out).unwrap();
+                out
+            });
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_build, bench_random_reads, bench_sequential);
+criterion_main!(benches);
@@ -0,0 +1,605 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::exit;
+
+use zran_rs::blocks::scan_blocks;
+use zran_rs::embed::read_appended_index;
+use zran_rs::reader::SeekableZLibReader;
+use zran_rs::search::search;
+use zran_rs::source::{FileSource, RandomAccessSource, RangeSource, SourceReader};
+use zran_rs::types::{CompressionMode, DeflateIndex, Point, SPAN};
+use zran_rs::zran::{IndexBuilder, PointDecoder, TrailingData};
+
+const USAGE: &str = "\
+Usage: zran <command> [options] <file>
+       zran grep [options] <pattern> <file>
+
+Commands:
+  index     Build an index of <file> and save it
+  info      Print a summary of the index, its access points and gzip members
+  extract   Write a byte or line range of the uncompressed data to stdout
+  cat       Decompress <file> to stdout through the index
+  verify    Check the index against <file>
+  check     Test the integrity of the compressed stream
+  grep      Print the offset of every occurrence of <pattern>, with the zero
+            based line number and the line when the index counts lines
+
+Options:
+  -i, --index <path>      Index to use (default <file>.zri, then an index
+                          embedded in <file>)
+  -o, --output <path>     Where `index` saves the index (default <file>.zri)
+  -s, --span <bytes>      Distance between access points (default 1048576)
+      --count-lines       Count newlines while indexing
+      --delimiter <byte>  Count records ending in <byte> (0-255) instead
+      --record-aligned    Only put access points at record starts
+  -m, --mode <mode>       Format of <file>: gzip, zlib or raw (default:
+                          detected from the header)
+      --trailing <policy> Data after the last gzip member: error, stop or
+                          skip-zeros (default error)
+  -b, --bytes <a:b>       Byte range for `extract`, end exclusive and optional
+  -l, --lines <a:b>       Zero based line range for `extract`
+  -p, --points            List every access point in `info`
+      --blocks            Scan the deflate blocks of every gzip member in
+                          `info`, which decodes all of <file>
+  -j, --threads <n>       Worker threads for `grep` (default: all cores)
+";
+
+#[derive(Default)]
+struct Options {
+    command: String,
+    file: PathBuf,
+    pattern: String,
+    index: Option<PathBuf>,
+    output: Option<PathBuf>,
+    span: Option<u64>,
+    delimiter: Option<u8>,
+    record_aligned: bool,
+    mode: Option<CompressionMode>,
+    trailing: Option<TrailingData>,
+    bytes: Option<(u64, Option<u64>)>,
+    lines: Option<(u64, Option<u64>)>,
+    points: bool,
+    blocks: bool,
+    threads: Option<usize>,
+}
+
+fn main() {
+    let options = match parse_args(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(msg) => {
+            eprintln!("zran: {}\n\n{}", msg, USAGE);
+            exit(2);
+        }
+    };
+
+    let result = match options.command.as_str() {
+        "index" => index(&options),
+        "info" => info(&options),
+        "extract" => extract(&options),
+        "cat" => cat(&options),
+        "verify" => verify(&options),
+        "check" => check(&options),
+        "grep" => grep(&options),
+        _ => unreachable!(),
+    };
+
+    match result {
+        Ok(true) => {}
+        Ok(false) => exit(1),
+        Err(e) if e.kind() == io::ErrorKind::BrokenPipe => {}
+        Err(e) => {
+            eprintln!("zran: {}", e);
+            exit(1);
+        }
+    }
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Options, String> {
+    let mut options = Options::default();
+    let mut positional = vec![];
+
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| {
+            args.next()
+                .ok_or_else(|| format!("Missing value for {}", name))
+        };
+        match arg.as_str() {
+            "-h" | "--help" => {
+                print!("{}", USAGE);
+                exit(0);
+            }
+            "-i" | "--index" => options.index = Some(value(&arg)?.into()),
+            "-o" | "--output" => options.output = Some(value(&arg)?.into()),
+            "-s" | "--span" => options.span = Some(parse_number(&value(&arg)?)?),
+            "--count-lines" => options.delimiter = Some(b'\n'),
+            "--delimiter" => {
+                let byte = value(&arg)?;
+                let byte = byte
+                    .parse()
+                    .map_err(|_| format!("Invalid delimiter: {}", byte))?;
+                options.delimiter = Some(byte);
+            }
+            "--record-aligned" => options.record_aligned = true,
+            "-m" | "--mode" => {
+                let mode = value(&arg)?;
+                options.mode = Some(match mode.as_str() {
+                    "gzip" => CompressionMode::Gzip,
+                    "zlib" => CompressionMode::Zlib,
+                    "raw" => CompressionMode::Raw,
+                    _ => return Err(format!("Invalid mode: {}", mode)),
+                });
+            }
+            "--trailing" => {
+                let policy = value(&arg)?;
+                options.trailing = Some(match policy.as_str() {
+                    "error" => TrailingData::Error,
+                    "stop" => TrailingData::Stop,
+                    "skip-zeros" => TrailingData::SkipZeros,
+                    _ => return Err(format!("Invalid trailing data policy: {}", policy)),
+                });
+            }
+            "-b" | "--bytes" => options.bytes = Some(parse_range(&value(&arg)?)?),
+            "-l" | "--lines" => options.lines = Some(parse_range(&value(&arg)?)?),
+            "-p" | "--points" => options.points = true,
+            "--blocks" => options.blocks = true,
+            "-j" | "--threads" => {
+                let threads = parse_number(&value(&arg)?)?;
+                options.threads = Some(threads as usize);
+            }
+            _ if arg.starts_with('-') && arg.len() > 1 => {
+                return Err(format!("Unknown option: {}", arg))
+            }
+            _ if options.command.is_empty() => options.command = arg,
+            _ => positional.push(arg),
+        }
+    }
+
+    let expected = match options.command.as_str() {
+        "index" | "info" | "extract" | "cat" | "verify" | "check" => 1,
+        "grep" => 2,
+        "" => return Err("Missing command".to_string()),
+        command => return Err(format!("Unknown command: {}", command)),
+    };
+    if positional.len() > expected {
+        return Err(format!("Unexpected argument: {}", positional[expected]));
+    }
+    options.file = positional.pop().ok_or("Missing file")?.into();
+    if expected == 2 {
+        options.pattern = positional.pop().ok_or("Missing pattern")?;
+        if options.pattern.is_empty() {
+            return Err("Empty pattern".to_string());
+        }
+    }
+    if options.command == "extract" && options.bytes.is_some() == options.lines.is_some() {
+        return Err("extract needs either --bytes or --lines".to_string());
+    }
+    Ok(options)
+}
+
+fn parse_number(value: &str) -> Result<u64, String> {
+    value
+        .parse()
+        .map_err(|_| format!("Invalid number: {}", value))
+}
+
+// Parses `start:end`, where either side may be left out
+fn parse_range(value: &str) -> Result<(u64, Option<u64>), String> {
+    let (start, end) = value.split_once(':').unwrap_or((value, ""));
+    let start = if start.is_empty() {
+        0
+    } else {
+        parse_number(start)?
+    };
+    let end = if end.is_empty() {
+        None
+    } else {
+        Some(parse_number(end)?)
+    };
+    match end {
+        Some(end) if end < start => Err(format!("Invalid range: {}", value)),
+        _ => Ok((start, end)),
+    }
+}
+
+fn default_index_path(file: &Path) -> PathBuf {
+    let mut path = file.as_os_str().to_owned();
+    path.push(".zri");
+    PathBuf::from(path)
+}
+
+fn load_index(options: &Options, source: &FileSource) -> io::Result<DeflateIndex> {
+    let path = options
+        .index
+        .clone()
+        .unwrap_or_else(|| default_index_path(&options.file));
+    if options.index.is_some() || path.exists() {
+        return DeflateIndex::deserialize(&mut io::BufReader::new(File::open(path)?));
+    }
+
+    match read_appended_index(source)? {
+        Some((index, _)) => Ok(index),
+        None => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!(
+                "No index found for {}, run `zran index` first",
+                options.file.display()
+            ),
+        )),
+    }
+}
+
+fn open_reader(options: &Options) -> io::Result<SeekableZLibReader<FileSource>> {
+    let source = FileSource::open(&options.file)?;
+    let index = load_index(options, &source)?;
+    Ok(SeekableZLibReader::from_source(source, index))
+}
+
+fn builder(options: &Options) -> IndexBuilder {
+    let mut builder = IndexBuilder::new().span(options.span.unwrap_or(SPAN));
+    if let Some(delimiter) = options.delimiter {
+        builder = builder.delimiter(delimiter);
+    }
+    if options.record_aligned {
+        builder = builder.record_aligned();
+    }
+    if let Some(mode) = options.mode {
+        builder = builder.mode(mode);
+    }
+    if let Some(trailing) = options.trailing {
+        builder = builder.trailing_data(trailing);
+    }
+    builder
+}
+
+fn mode_name(mode: CompressionMode) -> &'static str {
+    match mode {
+        CompressionMode::Gzip => "gzip",
+        CompressionMode::Zlib => "zlib",
+        CompressionMode::Raw => "raw deflate",
+        CompressionMode::Auto => "unknown",
+    }
+}
+
+fn index(options: &Options) -> io::Result<bool> {
+    let index = builder(options).build(&mut File::open(&options.file)?)?;
+    let path = options
+        .output
+        .clone()
+        .unwrap_or_else(|| default_index_path(&options.file));
+
+    let mut writer = BufWriter::new(File::create(&path)?);
+    index.serialize(&mut writer)?;
+    writer.flush()?;
+    eprintln!(
+        "{}: {} access points, {} bytes",
+        path.display(),
+        index.list.len(),
+        index.length
+    );
+    Ok(true)
+}
+
+fn info(options: &Options) -> io::Result<bool> {
+    let reader = open_reader(options)?;
+    let index = reader.index();
+    let compressed = FileSource::open(&options.file)?.len()?;
+    let stats = index.stats();
+
+    println!("mode:          {}", mode_name(index.mode));
+    println!("uncompressed:  {}", index.length);
+    println!(
+        "compressed:    {} ({:.1}%)",
+        compressed,
+        compressed as f64 * 100.0 / std::cmp::max(index.length, 1) as f64
+    );
+    println!(
+        "access points: {} (average span {}, max span {}, {} window bytes)",
+        stats.points, stats.average_span, stats.max_span, stats.window_bytes
+    );
+    println!(
+        "seek cost:     up to {} bytes read and inflated",
+        stats.worst_seek_cost
+    );
+    println!("window size:   {}", index.window_size);
+    if index.data_end != 0 && index.data_end < compressed {
+        println!(
+            "trailing data: {} bytes from offset {}",
+            compressed - index.data_end,
+            index.data_end
+        );
+    }
+    match index.delimiter {
+        Some(delimiter) => println!(
+            "lines:         {} (delimiter {:#04x})",
+            index.lines, delimiter
+        ),
+        None => println!("lines:         not counted"),
+    }
+
+    if index.mode == CompressionMode::Gzip {
+        println!("members:       {}", index.members);
+    }
+
+    if options.blocks {
+        print_members(options, index)?;
+    }
+    if options.points {
+        println!(
+            "{:>6} {:>14} {:>14} {:>4} {:>8}",
+            "point", "compressed", "uncompressed", "bits", "window"
+        );
+        for (i, point) in index.list.iter().enumerate() {
+            println!(
+                "{:>6} {:>14} {:>14} {:>4} {:>8}",
+                i,
+                point.inn,
+                point.out,
+                point.bits,
+                point.window.len()
+            );
+        }
+    }
+    Ok(true)
+}
+
+// Lists the gzip members with the deflate blocks in them
+fn print_members(options: &Options, index: &DeflateIndex) -> io::Result<()> {
+    // Leave out trailing data, which does not decode
+    let source = FileSource::open(&options.file)?;
+    let end = match index.data_end {
+        0 => source.len()?,
+        end => end,
+    };
+    let data = RangeSource::new(source, 0, end);
+    let blocks = scan_blocks(&mut SourceReader::new(data), index.mode)?;
+    let members = blocks.last().map_or(0, |block| block.member + 1);
+    for member in 0..members {
+        let blocks: Vec<_> = blocks
+            .iter()
+            .filter(|block| block.member == member)
+            .collect();
+        let (first, last) = (blocks[0], blocks[blocks.len() - 1]);
+        println!(
+            "  member {}: compressed from {}, uncompressed {}..{}, {} blocks",
+            member,
+            first.offset / 8,
+            first.out,
+            last.out + last.uncompressed_size,
+            blocks.len()
+        );
+    }
+
+    Ok(())
+}
+
+fn extract(options: &Options) -> io::Result<bool> {
+    let source = FileSource::open(&options.file)?;
+    let reader = SeekableZLibReader::from_source(&source, load_index(options, &source)?);
+    let length = reader.index().length;
+
+    let (start, end) = match (options.bytes, options.lines) {
+        (Some((start, end)), _) => (start, end.unwrap_or(length)),
+        (_, Some((start, end))) => {
+            let lines = reader.index().lines;
+            let start = reader.line_offset(std::cmp::min(start, lines))?;
+            let end = match end {
+                Some(end) => reader.line_offset(std::cmp::min(end, lines))?,
+                None => length,
+            };
+            (start, end)
+        }
+        _ => unreachable!(),
+    };
+
+    write_range(&source, reader.index(), start, std::cmp::min(end, length))?;
+    Ok(true)
+}
+
+fn cat(options: &Options) -> io::Result<bool> {
+    let source = FileSource::open(&options.file)?;
+    let index = load_index(options, &source)?;
+    write_range(&source, &index, 0, index.length)?;
+    Ok(true)
+}
+
+// Writes the uncompressed bytes start..end to stdout, decoding from the
+// access point before start in one pass
+fn write_range(source: &FileSource, index: &DeflateIndex, start: u64, end: u64) -> io::Result<()> {
+    if start >= end {
+        return Ok(());
+    }
+    let point = match index.locate(start) {
+        Some(k) => &index.list[k],
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Index has no access points",
+            ))
+        }
+    };
+    let mut decoder = PointDecoder::new(source, index, point)?;
+    io::copy(&mut (&mut decoder).take(start - point.out), &mut io::sink())?;
+
+    let mut stdout = BufWriter::new(io::stdout().lock());
+    let copied = io::copy(&mut decoder.take(end - start), &mut stdout)?;
+    stdout.flush()?;
+    if copied < end - start {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "Data ends before the indexed length",
+        ));
+    }
+    Ok(())
+}
+
+// Decodes the data between each pair of access points, starting afresh at
+// every point, and compares it with what the index records.
+fn verify(options: &Options) -> io::Result<bool> {
+    let source = FileSource::open(&options.file)?;
+    let index = load_index(options, &source)?;
+    let compressed = source.len()?;
+    let mut good = true;
+
+    for (i, point) in index.list.iter().enumerate() {
+        let (end, end_lines) = match index.list.get(i + 1) {
+            Some(next) => (next.out, next.lines),
+            None => (index.length, index.lines),
+        };
+        let mut problem = None;
+        if point.inn > compressed {
+            problem = Some("compressed offset past the end of the file".to_string());
+        } else if let Some(len) = end.checked_sub(point.out) {
+            match decode_span(&source, &index, point, len) {
+                Ok(lines) => {
+                    let total = point.lines.checked_add(lines);
+                    if index.delimiter.is_some() && total != Some(end_lines) {
+                        problem = Some(format!(
+                            "{} lines at the end of the span, expected {}",
+                            point.lines.saturating_add(lines),
+                            end_lines
+                        ));
+                    }
+                }
+                Err(e) => problem = Some(e.to_string()),
+            }
+        } else {
+            problem = Some(format!("uncompressed offset past the span end {}", end));
+        }
+
+        if let Some(problem) = problem {
+            println!("point {} at {}: {}", i, point.out, problem);
+            good = false;
+        }
+    }
+
+    if good {
+        println!(
+            "{}: index ok, {} access points",
+            options.file.display(),
+            index.list.len()
+        );
+    }
+    Ok(good)
+}
+
+// Decodes `len` bytes from `point` and counts the delimiters in them
+fn decode_span(
+    source: &FileSource,
+    index: &DeflateIndex,
+    point: &Point,
+    len: u64,
+) -> io::Result<u64> {
+    let mut decoder = PointDecoder::new(source, index, point)?;
+    let mut chunk = vec![0; 65536];
+    let mut remaining = len;
+    let mut lines = 0;
+    while remaining > 0 {
+        let want = std::cmp::min(remaining, chunk.len() as u64) as usize;
+        let n = decoder.read(&mut chunk[..want])?;
+        if n == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("data ends {} bytes early", remaining),
+            ));
+        }
+        if let Some(delimiter) = index.delimiter {
+            lines += chunk[..n].iter().filter(|&&b| b == delimiter).count() as u64;
+        }
+        remaining -= n as u64;
+    }
+    Ok(lines)
+}
+
+fn check(options: &Options) -> io::Result<bool> {
+    let recovered = builder(options).build_tolerant(&mut File::open(&options.file)?)?;
+    for range in &recovered.damaged {
+        println!(
+            "damaged: compressed {}..{} at uncompressed {}: {}",
+            range.start, range.end, range.out, range.error
+        );
+    }
+    if recovered.damaged.is_empty() {
+        println!(
+            "{}: ok, {} bytes uncompressed",
+            options.file.display(),
+            recovered.index.length
+        );
+    }
+    Ok(recovered.damaged.is_empty())
+}
+
+fn grep(options: &Options) -> io::Result<bool> {
+    let source = FileSource::open(&options.file)?;
+    let index = load_index(options, &source)?;
+    let threads = options
+        .threads
+        .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get()));
+    let matches = search(&source, &index, options.pattern.as_bytes(), threads)?;
+
+    let mut stdout = BufWriter::new(io::stdout().lock());
+    let mut last_line = None;
+    for m in &matches {
+        match m.line {
+            // One output line per matching line, like grep
+            Some(line) if last_line == Some(line) => {}
+            Some(line) => {
+                write!(stdout, "{}:{}:", line, m.offset)?;
+                stdout.write_all(&m.text)?;
+                writeln!(stdout)?;
+                last_line = Some(line);
+            }
+            None => writeln!(stdout, "{}", m.offset)?,
+        }
+    }
+    stdout.flush()?;
+    Ok(!matches.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &str) -> Result<Options, String> {
+        parse_args(args.split_whitespace().map(String::from))
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let options = parse("index -s 65536 --count-lines data.gz").unwrap();
+        assert_eq!(options.command, "index");
+        assert_eq!(options.file, PathBuf::from("data.gz"));
+        assert_eq!(options.span, Some(65536));
+        assert_eq!(options.delimiter, Some(b'\n'));
+        assert_eq!(options.mode, None);
+        let options = parse("check -m raw data.deflate").unwrap();
+        assert_eq!(options.mode, Some(CompressionMode::Raw));
+        assert!(parse("check --mode lzma data.xz").is_err());
+        let options = parse("index --trailing skip-zeros disk.img").unwrap();
+        assert_eq!(options.trailing, Some(TrailingData::SkipZeros));
+
+        let options = parse("extract data.gz --lines 10:").unwrap();
+        assert_eq!(options.lines, Some((10, None)));
+        assert_eq!(
+            parse("extract -b :5 data.gz").unwrap().bytes,
+            Some((0, Some(5)))
+        );
+
+        assert!(parse("extract data.gz").is_err());
+        assert!(parse("extract -b 5:1 data.gz").is_err());
+        assert!(parse("index").is_err());
+        assert!(parse("frobnicate data.gz").is_err());
+        assert!(parse("cat --span data.gz").is_err());
+        assert!(parse("cat a.gz b.gz").is_err());
+
+        let options = parse("grep -j 4 needle data.gz").unwrap();
+        assert_eq!(options.pattern, "needle");
+        assert_eq!(options.file, PathBuf::from("data.gz"));
+        assert_eq!(options.threads, Some(4));
+        assert!(parse("grep data.gz").is_err());
+
+        let options = parse("info --blocks -p data.gz").unwrap();
+        assert!(options.blocks && options.points);
+        assert!(!parse("info data.gz").unwrap().blocks);
+    }
+}
@@ -0,0 +1,170 @@
+use std::io::{self, Read, Seek, SeekFrom};
+
+use libz_rs_sys::{
+    inflate, inflateEnd, inflateInit2, inflateReset2, z_stream, Z_BLOCK, Z_OK, Z_STREAM_END,
+};
+
+use crate::pushback::PushbackReader;
+use crate::types::{CompressionMode, CHUNK, WINSIZE};
+use crate::zran::{detect_mode, fread, is_eof, new_z_stream, zlib_error_description};
+
+/// How a deflate block encodes its data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockType {
+    Stored,
+    Fixed,
+    Dynamic,
+}
+
+/// A deflate block found by `scan_blocks`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeflateBlock {
+    pub offset: u64, // compressed offset of the block header, in bits
+    pub out: u64,    // uncompressed offset of the block data
+    pub kind: BlockType,
+    pub last: bool,             // BFINAL, the block ends its deflate stream
+    pub compressed_bits: u64,   // size including the header
+    pub uncompressed_size: u64, // bytes the block decodes to
+    pub member: usize,          // gzip member the block belongs to, 0 otherwise
+}
+
+impl DeflateBlock {
+    /// Compressed byte offset of the block header and the number of bits of
+    /// the previous byte it starts with, as stored in an access point.
+    pub fn position(&self) -> (u64, u32) {
+        let inn = self.offset.div_ceil(8);
+        (inn, (inn * 8 - self.offset) as u32)
+    }
+}
+
+// A block boundary seen while inflating
+struct Boundary {
+    offset: u64, // in bits
+    out: u64,
+    member: usize,
+    end: bool, // end of the last block of a deflate stream
+}
+
+/// Walks a raw deflate, zlib or gzip stream and reports every deflate block
+/// in it, following gzip members to the end of the input. `mode` gives the
+/// format, or `CompressionMode::Auto` to detect it from the header as
+/// `IndexBuilder` does. Decodes the whole stream, so any error in the data
+/// is returned.
+pub fn scan_blocks<R: Read + Seek>(
+    reader: &mut R,
+    mode: CompressionMode,
+) -> io::Result<Vec<DeflateBlock>> {
+    let boundaries = find_boundaries(reader, mode)?;
+
+    let mut blocks: Vec<DeflateBlock> = vec![];
+    for pair in boundaries.windows(2) {
+        let (start, end) = (&pair[0], &pair[1]);
+        if start.end {
+            continue;
+        }
+
+        // The block header is the three bits at the boundary
+        let mut bytes = [0; 2];
+        reader.seek(SeekFrom::Start(start.offset / 8))?;
+        fread(reader, &mut bytes, 2)?;
+        let header = (u16::from_le_bytes(bytes) >> (start.offset % 8)) & 7;
+
+        blocks.push(DeflateBlock {
+            offset: start.offset,
+            out: start.out,
+            kind: match header >> 1 {
+                0 => BlockType::Stored,
+                1 => BlockType::Fixed,
+                _ => BlockType::Dynamic, // inflate already rejected type 3
+            },
+            last: header & 1 == 1,
+            compressed_bits: end.offset - start.offset,
+            uncompressed_size: end.out - start.out,
+            member: start.member,
+        });
+    }
+
+    Ok(blocks)
+}
+
+// Inflates the stream, stopping at every block boundary. Each deflate stream
+// contributes its start and the end of every block.
+fn find_boundaries<R: Read + Seek>(
+    reader: &mut R,
+    mode: CompressionMode,
+) -> io::Result<Vec<Boundary>> {
+    let mut in_stream = PushbackReader::new(reader);
+    let mut stream: z_stream = new_z_stream();
+    let mut buffer = vec![0; CHUNK];
+    let mut win = vec![0; WINSIZE];
+    let mut totin = 0u64;
+    let mut totout = 0u64;
+    let mut member = 0;
+    let mut boundaries = vec![];
+
+    unsafe {
+        stream.avail_in = fread(&mut in_stream, &mut buffer, CHUNK)? as u32;
+        totin += stream.avail_in as u64;
+        stream.next_in = buffer.as_mut_ptr();
+
+        let mode = match mode {
+            CompressionMode::Auto => detect_mode(&stream),
+            mode => mode,
+        };
+        let mut ret = inflateInit2(&mut stream, mode as i32);
+        if mode == CompressionMode::Raw {
+            // Raw data has no header for inflate to stop after
+            boundaries.push(Boundary {
+                offset: 0,
+                out: 0,
+                member,
+                end: false,
+            });
+        }
+
+        while ret == Z_OK {
+            if stream.avail_in == 0 {
+                stream.avail_in = fread(&mut in_stream, &mut buffer, CHUNK)? as u32;
+                totin += stream.avail_in as u64;
+                stream.next_in = buffer.as_mut_ptr();
+            }
+            if stream.avail_out == 0 {
+                stream.avail_out = WINSIZE as u32;
+                stream.next_out = win.as_mut_ptr();
+            }
+
+            let before = stream.avail_out;
+            ret = inflate(&mut stream, Z_BLOCK);
+            totout += (before - stream.avail_out) as u64;
+
+            if ret == Z_OK && stream.data_type & 0x80 != 0 {
+                let consumed = totin - stream.avail_in as u64;
+                boundaries.push(Boundary {
+                    offset: consumed * 8 - (stream.data_type & 7) as u64,
+                    out: totout,
+                    member,
+                    end: stream.data_type & 0x40 != 0,
+                });
+            }
+
+            if ret == Z_STREAM_END
+                && mode == CompressionMode::Gzip
+                && (stream.avail_in != 0 || !is_eof(&mut in_stream)?)
+            {
+                // Another gzip member follows
+                ret = inflateReset2(&mut stream, CompressionMode::Gzip as i32);
+                member += 1;
+            }
+        }
+
+        inflateEnd(&mut stream);
+        if ret != Z_STREAM_END {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("zlib error: {}", zlib_error_description(ret)),
+            ));
+        }
+    }
+
+    Ok(boundaries)
+}
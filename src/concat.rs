@@ -0,0 +1,127 @@
+// Several compressed files read as one stream, such as rotated logs. Each
+// file keeps its own index; a global uncompressed offset is mapped to a file
+// and an offset in it, and the reader of that file finds the access point.
+
+use std::io::{self, BufRead, Read, Seek, SeekFrom};
+
+use crate::reader::SeekableZLibReader;
+use crate::source::RandomAccessSource;
+use crate::types::DeflateIndex;
+
+/// Reads the uncompressed data of several files in order, as if it were
+/// one stream.
+pub struct ConcatSeekableReader<S: RandomAccessSource> {
+    readers: Vec<SeekableZLibReader<S>>,
+    starts: Vec<u64>, // global offset of the first byte of each file
+    length: u64,
+    current_offset: u64,
+}
+
+impl<S: RandomAccessSource> ConcatSeekableReader<S> {
+    pub fn new(parts: Vec<(S, DeflateIndex)>) -> Self {
+        Self::from_readers(
+            parts
+                .into_iter()
+                .map(|(source, index)| SeekableZLibReader::from_source(source, index))
+                .collect(),
+        )
+    }
+
+    /// Concatenates readers that are already set up, e.g. with custom
+    /// buffer sizes. Their current positions do not matter.
+    pub fn from_readers(readers: Vec<SeekableZLibReader<S>>) -> Self {
+        let mut starts = Vec::with_capacity(readers.len());
+        let mut length = 0;
+        for reader in &readers {
+            starts.push(length);
+            length += reader.index().length;
+        }
+        Self {
+            readers,
+            starts,
+            length,
+            current_offset: 0,
+        }
+    }
+
+    /// Total uncompressed length of all files.
+    pub fn len(&self) -> u64 {
+        self.length
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+
+    pub fn readers(&self) -> &[SeekableZLibReader<S>] {
+        &self.readers
+    }
+
+    /// Returns the file holding the byte at global `offset`, and the offset
+    /// of that byte in its file, or None past the end.
+    pub fn locate(&self, offset: u64) -> Option<(usize, u64)> {
+        if offset >= self.length {
+            return None;
+        }
+        // The last file starting at or before offset; empty files before it
+        // start at the same offset
+        let part = self.starts.partition_point(|&start| start <= offset) - 1;
+        Some((part, offset - self.starts[part]))
+    }
+}
+
+impl<S: RandomAccessSource> Read for ConcatSeekableReader<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let to_copy = std::cmp::min(buf.len(), available.len());
+        buf[..to_copy].copy_from_slice(&available[..to_copy]);
+        self.consume(to_copy);
+
+        Ok(to_copy)
+    }
+}
+
+impl<S: RandomAccessSource> BufRead for ConcatSeekableReader<S> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        let Some((part, offset)) = self.locate(self.current_offset) else {
+            return Ok(&[]);
+        };
+        // Keeps the buffer of the file when the offset is inside it
+        let reader = &mut self.readers[part];
+        reader.seek(SeekFrom::Start(offset))?;
+        reader.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        if let Some((part, offset)) = self.locate(self.current_offset) {
+            let remaining = self.readers[part].index().length - offset;
+            let amt = std::cmp::min(amt as u64, remaining);
+            self.readers[part].consume(amt as usize);
+            self.current_offset += amt;
+        }
+    }
+}
+
+impl<S: RandomAccessSource> Seek for ConcatSeekableReader<S> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let offset = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.length.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.current_offset.checked_add_signed(offset),
+        }
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Invalid seek to a negative or overflowing position",
+            )
+        })?;
+
+        // The file readers are positioned when reading
+        self.current_offset = offset;
+        Ok(offset)
+    }
+
+    fn stream_position(&mut self) -> io::Result<u64> {
+        Ok(self.current_offset)
+    }
+}
@@ -0,0 +1,134 @@
+// Storage of a `DeflateIndex` inside the gzip file it indexes.
+//
+// The index is appended as a run of empty gzip members whose FEXTRA field
+// carries the serialized index in chunks (subfield `ZI`), followed by a
+// fixed size locator member (subfield `ZL`) holding the offset and length
+// of the index. Ordinary gzip tools decompress these members to nothing.
+
+use std::io::{self, Cursor, Write};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::source::RandomAccessSource;
+use crate::types::DeflateIndex;
+
+const INDEX_ID: [u8; 2] = *b"ZI";
+const LOCATOR_ID: [u8; 2] = *b"ZL";
+const MAX_PAYLOAD: usize = 65535 - 4; // XLEN minus the subfield header
+const MEMBER_OVERHEAD: usize = 10 + 2 + 4 + 2 + 8; // header, XLEN, subfield, data, trailer
+const LOCATOR_LEN: usize = MEMBER_OVERHEAD + 16;
+const MAX_INDEX_SIZE: u64 = 1 << 32; // larger embedded indexes are taken as corrupt
+
+/// Bytes needed by `is_index_member` to recognize a member.
+pub(crate) const MEMBER_PEEK: usize = 14;
+
+/// Appends `index` to a gzip stream of `offset` compressed bytes.
+pub fn append_index<W: Write>(writer: &mut W, index: &DeflateIndex, offset: u64) -> io::Result<()> {
+    let mut serialized = vec![];
+    index.serialize(&mut serialized)?;
+
+    for chunk in serialized.chunks(MAX_PAYLOAD) {
+        write_member(writer, INDEX_ID, chunk)?;
+    }
+
+    let mut locator = vec![];
+    locator.write_u64::<LittleEndian>(offset)?;
+    locator.write_u64::<LittleEndian>(serialized.len() as u64)?;
+    write_member(writer, LOCATOR_ID, &locator)
+}
+
+/// Reads an index appended with `append_index`. Returns the index and the
+/// offset where the index members begin, or None if the source has no
+/// embedded index.
+pub fn read_appended_index<S: RandomAccessSource + ?Sized>(
+    source: &S,
+) -> io::Result<Option<(DeflateIndex, u64)>> {
+    let len = source.len()?;
+    if len < LOCATOR_LEN as u64 {
+        return Ok(None);
+    }
+
+    let locator_offset = len - LOCATOR_LEN as u64;
+    let mut locator = vec![0; LOCATOR_LEN];
+    source.read_exact_at(locator_offset, &mut locator)?;
+    let mut payload = match member_payload(&locator, LOCATOR_ID) {
+        Some((payload, _)) if payload.len() == 16 => Cursor::new(payload),
+        _ => return Ok(None),
+    };
+    let offset = payload.read_u64::<LittleEndian>()?;
+    let size = payload.read_u64::<LittleEndian>()?;
+    if offset > locator_offset {
+        return Err(invalid("Invalid embedded index offset"));
+    }
+    // Every chunk of the index is one member between offset and the locator
+    let expected = size
+        .checked_add(size.div_ceil(MAX_PAYLOAD as u64) * MEMBER_OVERHEAD as u64)
+        .filter(|&expected| size <= MAX_INDEX_SIZE && expected == locator_offset - offset);
+    if expected.is_none() {
+        return Err(invalid("Invalid embedded index size"));
+    }
+
+    // Read one member at a time
+    let mut serialized = Vec::with_capacity(size as usize);
+    let mut member = vec![0; MAX_PAYLOAD + MEMBER_OVERHEAD];
+    let mut pos = offset;
+    while pos < locator_offset {
+        let len = std::cmp::min(member.len() as u64, locator_offset - pos) as usize;
+        source.read_exact_at(pos, &mut member[..len])?;
+        let (chunk, used) = member_payload(&member[..len], INDEX_ID)
+            .ok_or_else(|| invalid("Corrupt embedded index"))?;
+        if serialized.len() + chunk.len() > size as usize {
+            return Err(invalid("Corrupt embedded index"));
+        }
+        serialized.extend_from_slice(chunk);
+        pos += used as u64;
+    }
+    if serialized.len() as u64 != size {
+        return Err(invalid("Truncated embedded index"));
+    }
+
+    let index = DeflateIndex::deserialize(&mut Cursor::new(serialized))?;
+    Ok(Some((index, offset)))
+}
+
+/// Checks whether `data` starts with a member written by `append_index`.
+pub(crate) fn is_index_member(data: &[u8]) -> bool {
+    data.len() >= MEMBER_PEEK
+        && data[..4] == [0x1f, 0x8b, 8, 4]
+        && (data[12..14] == INDEX_ID || data[12..14] == LOCATOR_ID)
+}
+
+// Writes an empty gzip member carrying `payload` in an FEXTRA subfield.
+fn write_member<W: Write>(writer: &mut W, id: [u8; 2], payload: &[u8]) -> io::Result<()> {
+    // FEXTRA set, no timestamp, unknown OS
+    writer.write_all(&[0x1f, 0x8b, 8, 4, 0, 0, 0, 0, 0, 255])?;
+    writer.write_u16::<LittleEndian>(payload.len() as u16 + 4)?;
+    writer.write_all(&id)?;
+    writer.write_u16::<LittleEndian>(payload.len() as u16)?;
+    writer.write_all(payload)?;
+    // An empty final block, then the CRC and size of no data
+    writer.write_all(&[0x03, 0x00])?;
+    writer.write_all(&[0; 8])
+}
+
+// Parses a member written by `write_member`, returning its payload and the
+// number of bytes the member occupies.
+fn member_payload(data: &[u8], id: [u8; 2]) -> Option<(&[u8], usize)> {
+    if data.len() < 16 || !is_index_member(data) || data[12..14] != id {
+        return None;
+    }
+    let xlen = u16::from_le_bytes([data[10], data[11]]) as usize;
+    let len = u16::from_le_bytes([data[14], data[15]]) as usize;
+    let end = 12 + xlen;
+    if xlen != len + 4
+        || data.len() < end + 10
+        || data[end..end + 10] != [3, 0, 0, 0, 0, 0, 0, 0, 0, 0]
+    {
+        return None;
+    }
+    Some((&data[16..end], end + 10))
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
@@ -0,0 +1,434 @@
+use std::io::{self, Read};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use crate::source::RandomAccessSource;
+
+pub const DEFAULT_READ_AHEAD: usize = 256 * 1024;
+pub const DEFAULT_RETRIES: u32 = 3;
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+pub const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Remote source that fetches byte ranges with HTTP `Range:` requests.
+///
+/// Small sequential reads are coalesced into requests of at least
+/// `read_ahead` bytes, and every response is checked against the ETag seen
+/// when the source was opened so a replaced object is never mixed into a
+/// decode. The ETag is the fingerprint of the source, so an index built with
+/// `IndexBuilder::build_source` fails to read an object replaced since.
+pub struct HttpSource {
+    agent: ureq::Agent,
+    url: String,
+    len: u64,
+    etag: Option<String>,
+    read_ahead: usize,
+    retries: u32,
+    cache: Mutex<(u64, Vec<u8>)>, // offset and contents of the last fetched range
+}
+
+impl HttpSource {
+    /// Opens `url` with an agent that gives up on a stalled connection
+    /// after `DEFAULT_CONNECT_TIMEOUT` or `DEFAULT_READ_TIMEOUT`.
+    pub fn open(url: &str) -> io::Result<Self> {
+        let agent = ureq::AgentBuilder::new()
+            .timeout_connect(DEFAULT_CONNECT_TIMEOUT)
+            .timeout_read(DEFAULT_READ_TIMEOUT)
+            .build();
+        Self::with_agent(agent, url)
+    }
+
+    pub fn with_agent(agent: ureq::Agent, url: &str) -> io::Result<Self> {
+        let mut source = Self {
+            agent,
+            url: url.to_string(),
+            len: 0,
+            etag: None,
+            read_ahead: DEFAULT_READ_AHEAD,
+            retries: DEFAULT_RETRIES,
+            cache: Mutex::new((0, vec![])),
+        };
+
+        // Probe with a one byte range to learn the length and ETag
+        let response = source.get_range(0, 0)?;
+        source.len = match response.header("Content-Range") {
+            Some(range) => parse_content_range_length(range)?,
+            None => response
+                .header("Content-Length")
+                .and_then(|len| len.parse().ok())
+                .ok_or_else(|| invalid_data("Missing Content-Range in response"))?,
+        };
+        source.etag = response.header("ETag").map(str::to_string);
+
+        Ok(source)
+    }
+
+    /// Minimum number of bytes fetched per request.
+    pub fn with_read_ahead(mut self, read_ahead: usize) -> Self {
+        self.read_ahead = read_ahead;
+        self
+    }
+
+    /// Number of times a failed request is retried before giving up.
+    pub fn with_retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// Fails unless the remote object has the given ETag, for indexes that
+    /// were built without recording it.
+    pub fn expect_etag(self, etag: &str) -> io::Result<Self> {
+        match &self.etag {
+            Some(actual) if actual == etag => Ok(self),
+            actual => Err(invalid_data(&format!(
+                "ETag mismatch: expected {}, found {}",
+                etag,
+                actual.as_deref().unwrap_or("none")
+            ))),
+        }
+    }
+
+    pub fn etag(&self) -> Option<&str> {
+        self.etag.as_deref()
+    }
+
+    fn get_range(&self, start: u64, end: u64) -> io::Result<ureq::Response> {
+        let mut attempt = 0;
+        loop {
+            let request = self
+                .agent
+                .get(&self.url)
+                .set("Range", &format!("bytes={}-{}", start, end));
+
+            let error = match request.call() {
+                Ok(response) if response.status() == 206 || response.status() == 200 => {
+                    return Ok(response)
+                }
+                Ok(response) => io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("Unexpected HTTP status {}", response.status()),
+                ),
+                Err(ureq::Error::Status(code, _)) if code < 500 => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Other,
+                        format!("HTTP status {}", code),
+                    ))
+                }
+                Err(e) => io::Error::new(io::ErrorKind::Other, e.to_string()),
+            };
+
+            if attempt >= self.retries {
+                return Err(error);
+            }
+            attempt += 1;
+            thread::sleep(backoff(attempt));
+        }
+    }
+
+    fn fetch(&self, start: u64, end: u64) -> io::Result<Vec<u8>> {
+        let response = self.get_range(start, end - 1)?;
+
+        if self.etag.is_some() && response.header("ETag") != self.etag.as_deref() {
+            return Err(invalid_data("ETag changed while reading"));
+        }
+
+        // A server that ignores Range returns the whole object; skip to the
+        // range without buffering what comes before it
+        let skip = match response.status() {
+            200 => start,
+            _ => {
+                let range = response
+                    .header("Content-Range")
+                    .ok_or_else(|| invalid_data("Missing Content-Range in response"))?;
+                if parse_content_range_start(range)? != start {
+                    return Err(invalid_data("HTTP range response at the wrong offset"));
+                }
+                0
+            }
+        };
+        let mut reader = response.into_reader();
+        io::copy(&mut reader.by_ref().take(skip), &mut io::sink())?;
+        let mut body = vec![];
+        reader.take(end - start).read_to_end(&mut body)?;
+
+        if body.len() as u64 != end - start {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Short HTTP range response",
+            ));
+        }
+        Ok(body)
+    }
+}
+
+impl RandomAccessSource for HttpSource {
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.len)
+    }
+
+    fn fingerprint(&self) -> Option<String> {
+        self.etag.clone()
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        if offset >= self.len || buf.is_empty() {
+            return Ok(0);
+        }
+
+        let mut cache = self
+            .cache
+            .lock()
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "Source lock poisoned"))?;
+
+        let cached = offset >= cache.0 && offset < cache.0 + cache.1.len() as u64;
+        if !cached {
+            let want = std::cmp::max(buf.len(), self.read_ahead) as u64;
+            let end = std::cmp::min(offset + want, self.len);
+            *cache = (offset, self.fetch(offset, end)?);
+        }
+
+        let start = (offset - cache.0) as usize;
+        let n = std::cmp::min(buf.len(), cache.1.len() - start);
+        buf[..n].copy_from_slice(&cache.1[start..start + n]);
+        Ok(n)
+    }
+}
+
+// Doubles from 100 ms up to about 10 s
+fn backoff(attempt: u32) -> Duration {
+    Duration::from_millis(50 << std::cmp::min(attempt, 8))
+}
+
+fn parse_content_range_start(range: &str) -> io::Result<u64> {
+    // Content-Range: bytes 100-199/12345
+    range
+        .trim()
+        .strip_prefix("bytes ")
+        .and_then(|range| range.split('-').next())
+        .and_then(|start| start.trim().parse().ok())
+        .ok_or_else(|| invalid_data("Invalid Content-Range"))
+}
+
+fn parse_content_range_length(range: &str) -> io::Result<u64> {
+    // Content-Range: bytes 0-0/12345
+    range
+        .rsplit('/')
+        .next()
+        .and_then(|len| len.trim().parse().ok())
+        .ok_or_else(|| invalid_data("Unknown length in Content-Range"))
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    // How the test server answers range requests
+    #[derive(Clone, Copy)]
+    enum Ranges {
+        Honored,
+        Ignored, // the whole object with status 200
+        Shifted, // a range one byte after the requested one
+    }
+
+    // Serves `data` with range support; the first `failures` requests get a 503
+    fn serve(data: Vec<u8>, etag: &'static str, failures: usize) -> (String, Arc<AtomicUsize>) {
+        serve_ranges(data, etag, failures, Ranges::Honored)
+    }
+
+    fn serve_ranges(
+        data: Vec<u8>,
+        etag: &'static str,
+        failures: usize,
+        ranges: Ranges,
+    ) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/data.gz", listener.local_addr().unwrap());
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut range = None;
+                for line in BufReader::new(&stream).lines() {
+                    let line = line.unwrap();
+                    if line.is_empty() {
+                        break;
+                    }
+                    if let Some(value) = line.to_ascii_lowercase().strip_prefix("range: bytes=") {
+                        let (start, end) = value.split_once('-').unwrap();
+                        range = Some((
+                            start.parse::<usize>().unwrap(),
+                            end.parse::<usize>().unwrap(),
+                        ));
+                    }
+                }
+
+                let n = counter.fetch_add(1, Ordering::SeqCst);
+                if n < failures {
+                    let _ = stream.write_all(
+                        b"HTTP/1.1 503 Unavailable\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    );
+                    continue;
+                }
+
+                let (start, end) = range.unwrap();
+                let start = match ranges {
+                    Ranges::Shifted => start + 1,
+                    _ => start,
+                };
+                let end = std::cmp::min(end, data.len() - 1);
+                let body = &data[start..=end];
+                let header = match ranges {
+                    Ranges::Ignored => format!(
+                        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nETag: {}\r\nConnection: close\r\n\r\n",
+                        data.len(), etag
+                    ),
+                    _ => format!(
+                        "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes {}-{}/{}\r\nContent-Length: {}\r\nETag: {}\r\nConnection: close\r\n\r\n",
+                        start, end, data.len(), body.len(), etag
+                    ),
+                };
+                let body = match ranges {
+                    Ranges::Ignored => &data[..],
+                    _ => body,
+                };
+                let _ = stream.write_all(header.as_bytes());
+                let _ = stream.write_all(body);
+            }
+        });
+
+        (url, requests)
+    }
+
+    #[test]
+    fn test_read_at_coalesces_requests() {
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+        let (url, requests) = serve(data.clone(), "\"v1\"", 0);
+
+        let source = HttpSource::open(&url).unwrap().with_read_ahead(32768);
+        assert_eq!(source.len().unwrap(), data.len() as u64);
+        assert_eq!(source.etag(), Some("\"v1\""));
+
+        let mut buffer = vec![0; 1000];
+        for offset in (50_000..60_000).step_by(1000) {
+            assert_eq!(source.read_at(offset, &mut buffer).unwrap(), 1000);
+            assert_eq!(&buffer[..], &data[offset as usize..offset as usize + 1000]);
+        }
+
+        // One probe plus a single coalesced range
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_unranged_responses() {
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+
+        // The prefix of a whole object response is skipped
+        let (url, _) = serve_ranges(data.clone(), "\"v1\"", 0, Ranges::Ignored);
+        let source = HttpSource::open(&url).unwrap().with_read_ahead(1000);
+        assert_eq!(source.len().unwrap(), data.len() as u64);
+        let mut buffer = vec![0; 1000];
+        assert_eq!(source.read_at(90_000, &mut buffer).unwrap(), 1000);
+        assert_eq!(&buffer[..], &data[90_000..91_000]);
+
+        // A range other than the one asked for is refused
+        let (url, _) = serve_ranges(data, "\"v1\"", 0, Ranges::Shifted);
+        let source = HttpSource::open(&url).unwrap();
+        let error = source.read_at(500, &mut buffer).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_retries_and_etag() {
+        let data = vec![7u8; 1000];
+        let (url, _) = serve(data, "\"v2\"", 2);
+
+        let source = HttpSource::open(&url).unwrap();
+        assert!(source.expect_etag("\"v1\"").is_err());
+
+        let source = HttpSource::open(&url)
+            .unwrap()
+            .expect_etag("\"v2\"")
+            .unwrap();
+        let mut buffer = [0; 10];
+        assert_eq!(source.read_at(990, &mut buffer).unwrap(), 10);
+        assert_eq!(buffer, [7; 10]);
+    }
+
+    #[test]
+    fn test_seek_remote_gzip() {
+        use crate::reader::SeekableZLibReader;
+        use crate::zran::build_index;
+        use std::io::{Cursor, Seek, SeekFrom};
+        use zlib_rs::deflate::{compress_slice, DeflateConfig};
+
+        let data: Vec<u8> = (0..200_000u32).map(|i| (i * 7 / 13) as u8).collect();
+        let config = DeflateConfig {
+            window_bits: 31,
+            ..DeflateConfig::default()
+        };
+        let mut output = vec![0; 300_000];
+        let compressed = compress_slice(&mut output, &data, config).0.to_vec();
+        let index = build_index(&mut Cursor::new(&compressed), 16384).unwrap();
+        let (url, _) = serve(compressed, "\"v1\"", 0);
+
+        let source = HttpSource::open(&url)
+            .unwrap()
+            .expect_etag("\"v1\"")
+            .unwrap();
+        let mut reader = SeekableZLibReader::from_source(source, index);
+        reader.seek(SeekFrom::Start(150_000)).unwrap();
+        let mut buffer = vec![0; 5000];
+        reader.read_exact(&mut buffer).unwrap();
+        assert_eq!(&buffer[..], &data[150_000..155_000]);
+    }
+
+    #[test]
+    fn test_index_fingerprint() {
+        use crate::reader::SeekableZLibReader;
+        use crate::zran::IndexBuilder;
+        use zlib_rs::deflate::{compress_slice, DeflateConfig};
+
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i * 7 / 13) as u8).collect();
+        let config = DeflateConfig {
+            window_bits: 31,
+            ..DeflateConfig::default()
+        };
+        let mut output = vec![0; 200_000];
+        let compressed = compress_slice(&mut output, &data, config).0.to_vec();
+        let (url, _) = serve(compressed.clone(), "\"v1\"", 0);
+        let (replaced, _) = serve(compressed, "\"v2\"", 0);
+
+        let source = HttpSource::open(&url).unwrap();
+        let index = IndexBuilder::new()
+            .span(16384)
+            .build_source(&source)
+            .unwrap();
+        assert_eq!(index.fingerprint.as_deref(), Some("\"v1\""));
+
+        let mut buffer = vec![0; 1000];
+        let mut reader = SeekableZLibReader::from_source(source, index.clone());
+        reader.read_exact(&mut buffer).unwrap();
+        assert_eq!(&buffer[..], &data[..1000]);
+
+        let source = HttpSource::open(&replaced).unwrap();
+        let mut reader = SeekableZLibReader::from_source(source, index);
+        let error = reader.read_exact(&mut buffer).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_backoff() {
+        assert_eq!(backoff(1), Duration::from_millis(100));
+        assert_eq!(backoff(u32::MAX), backoff(8));
+    }
+}
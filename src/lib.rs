@@ -1,6 +1,17 @@
+pub mod blocks;
+pub mod concat;
+pub mod embed;
+#[cfg(feature = "http")]
+pub mod http;
+pub mod parallel;
 mod pushback;
 pub mod reader;
+pub mod search;
+pub mod source;
+pub mod tar;
 pub mod types;
+pub mod writer;
+pub mod zip;
 pub mod zran;
 
 #[cfg(test)]
@@ -0,0 +1,291 @@
+use std::io::{self, Write};
+use std::thread;
+
+use byteorder::{BigEndian, LittleEndian, WriteBytesExt};
+use libz_rs_sys::{
+    adler32, crc32, deflate, deflateSetDictionary, Z_BUF_ERROR, Z_NO_FLUSH, Z_OK, Z_SYNC_FLUSH,
+};
+
+use crate::types::{CompressionMode, DeflateIndex, Point, CHUNK, WINSIZE};
+use crate::writer::{deflate_error, Deflater};
+
+/// Options for `ParallelGzWriter`.
+#[derive(Debug, Clone)]
+pub struct ParallelOptions {
+    pub mode: CompressionMode,
+    pub level: i32,
+    /// Uncompressed bytes per block; every block boundary is an access point.
+    pub block_size: usize,
+    /// Number of blocks compressed at once.
+    pub threads: usize,
+    /// Compress blocks without the previous 32 KiB as dictionary. Slightly
+    /// larger output, but the access points need no window.
+    pub independent: bool,
+}
+
+impl Default for ParallelOptions {
+    fn default() -> Self {
+        Self {
+            mode: CompressionMode::Gzip,
+            level: 6,
+            block_size: 128 * 1024,
+            threads: thread::available_parallelism().map_or(1, |n| n.get()),
+            independent: false,
+        }
+    }
+}
+
+/// Multi-threaded compressor in the style of pigz. Input is cut into blocks
+/// that are deflated concurrently, each primed with the 32 KiB before it,
+/// and concatenated into a single gzip, zlib or raw deflate stream. The
+/// block boundaries are byte aligned, so the index comes for free.
+pub struct ParallelGzWriter<W: Write> {
+    inner: W,
+    options: ParallelOptions,
+    pending: Vec<u8>, // input not compressed yet
+    history: Vec<u8>, // up to WINSIZE bytes of input before pending
+    check: u32,       // crc32 or adler32 of the input so far
+    total_in: u64,
+    total_out: u64,
+    index: DeflateIndex,
+}
+
+impl<W: Write> ParallelGzWriter<W> {
+    pub fn new(mut inner: W, options: ParallelOptions) -> io::Result<Self> {
+        if options.mode == CompressionMode::Auto {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "No compression mode given",
+            ));
+        }
+        let header = header(options.mode, options.level);
+        inner.write_all(&header)?;
+
+        let mut index = DeflateIndex::new();
+        index.mode = options.mode;
+        index.members = u64::from(options.mode == CompressionMode::Gzip);
+
+        Ok(Self {
+            inner,
+            pending: vec![],
+            history: vec![],
+            check: if options.mode == CompressionMode::Gzip {
+                0
+            } else {
+                1
+            },
+            total_in: 0,
+            total_out: header.len() as u64,
+            index,
+            options: ParallelOptions {
+                block_size: std::cmp::max(options.block_size, 1),
+                threads: std::cmp::max(options.threads, 1),
+                ..options
+            },
+        })
+    }
+
+    /// Compresses the remaining input, terminates the stream and returns the
+    /// inner writer together with the index of the stream.
+    pub fn finish(mut self) -> io::Result<(W, DeflateIndex)> {
+        self.compress_pending()?;
+        if self.index.list.is_empty() {
+            // Empty input still gets the access point after the header
+            let mut point = Point::new();
+            point.inn = self.total_out;
+            point.window = vec![];
+            self.index.list.push(point);
+        }
+
+        // An empty final block with fixed codes ends the deflate stream
+        self.inner.write_all(&[0x03, 0x00])?;
+        let trailer = match self.options.mode {
+            CompressionMode::Gzip => {
+                self.inner.write_u32::<LittleEndian>(self.check)?;
+                self.inner.write_u32::<LittleEndian>(self.total_in as u32)?;
+                8
+            }
+            CompressionMode::Zlib => {
+                self.inner.write_u32::<BigEndian>(self.check)?;
+                4
+            }
+            CompressionMode::Raw | CompressionMode::Auto => 0,
+        };
+        self.inner.flush()?;
+
+        self.index.length = self.total_in;
+        self.index.data_end = self.total_out + 2 + trailer;
+        Ok((self.inner, self.index))
+    }
+
+    // The up to WINSIZE bytes of input preceding the block at `start` in
+    // pending, which the block is primed with.
+    fn window_before(&self, start: usize) -> Vec<u8> {
+        if start >= WINSIZE {
+            return self.pending[start - WINSIZE..start].to_vec();
+        }
+        let from_history = std::cmp::min(WINSIZE - start, self.history.len());
+        let mut window = self.history[self.history.len() - from_history..].to_vec();
+        window.extend_from_slice(&self.pending[..start]);
+        window
+    }
+
+    // Compresses the pending input as blocks in parallel and writes them out
+    // in order.
+    fn compress_pending(&mut self) -> io::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+
+        let block_size = self.options.block_size;
+        let starts: Vec<usize> = (0..self.pending.len()).step_by(block_size).collect();
+        let dictionaries: Vec<Vec<u8>> = starts
+            .iter()
+            .map(|&start| {
+                if self.options.independent {
+                    vec![]
+                } else {
+                    self.window_before(start)
+                }
+            })
+            .collect();
+
+        let blocks = thread::scope(|scope| {
+            let workers: Vec<_> = starts
+                .iter()
+                .zip(&dictionaries)
+                .map(|(&start, dictionary)| {
+                    let end = std::cmp::min(start + block_size, self.pending.len());
+                    let data = &self.pending[start..end];
+                    let level = self.options.level;
+                    scope.spawn(move || compress_block(data, dictionary, level))
+                })
+                .collect();
+            workers
+                .into_iter()
+                .map(|worker| {
+                    worker.join().unwrap_or_else(|_| {
+                        Err(io::Error::new(
+                            io::ErrorKind::Other,
+                            "Compression thread panicked",
+                        ))
+                    })
+                })
+                .collect::<Vec<_>>()
+        });
+
+        for ((&start, block), window) in starts.iter().zip(blocks).zip(dictionaries) {
+            let block = block?;
+            let end = std::cmp::min(start + block_size, self.pending.len());
+            self.index.list.push(Point {
+                inn: self.total_out,
+                out: self.total_in,
+                bits: 0,
+                lines: 0,
+                record: self.total_in,
+                resync: false,
+                window,
+            });
+
+            self.check = update_check(self.options.mode, self.check, &self.pending[start..end]);
+            self.inner.write_all(&block)?;
+            self.total_in += (end - start) as u64;
+            self.total_out += block.len() as u64;
+        }
+
+        self.history = self.window_before(self.pending.len());
+        self.pending.clear();
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for ParallelGzWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let batch = self.options.block_size.saturating_mul(self.options.threads);
+        let take = std::cmp::min(buf.len(), batch - self.pending.len());
+        self.pending.extend_from_slice(&buf[..take]);
+        if self.pending.len() >= batch {
+            self.compress_pending()?;
+        }
+        Ok(take)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.compress_pending()?;
+        self.inner.flush()
+    }
+}
+
+// Raw deflates one block, ending it with a sync flush so the next block
+// starts on a byte boundary. Blocks of 4 GiB or more are fed to deflate in
+// pieces, since avail_in is 32 bits.
+fn compress_block(data: &[u8], dictionary: &[u8], level: i32) -> io::Result<Vec<u8>> {
+    let mut stream = Deflater::new(level, CompressionMode::Raw as i32)?;
+    let mut output = vec![];
+    let mut chunk = vec![0u8; CHUNK];
+
+    unsafe {
+        if !dictionary.is_empty() {
+            let ret =
+                deflateSetDictionary(&mut *stream, dictionary.as_ptr(), dictionary.len() as u32);
+            if ret != Z_OK {
+                return Err(deflate_error(ret));
+            }
+        }
+
+        let mut pieces = data.chunks(u32::MAX as usize).peekable();
+        loop {
+            if stream.avail_in == 0 {
+                if let Some(piece) = pieces.next() {
+                    stream.next_in = piece.as_ptr() as *mut u8;
+                    stream.avail_in = piece.len() as u32;
+                }
+            }
+            let last = pieces.peek().is_none();
+            stream.avail_out = chunk.len() as u32;
+            stream.next_out = chunk.as_mut_ptr();
+            let ret = deflate(&mut *stream, if last { Z_SYNC_FLUSH } else { Z_NO_FLUSH });
+            if ret != Z_OK && ret != Z_BUF_ERROR {
+                return Err(deflate_error(ret));
+            }
+            output.extend_from_slice(&chunk[..chunk.len() - stream.avail_out as usize]);
+            if last && stream.avail_in == 0 && stream.avail_out != 0 {
+                break;
+            }
+        }
+    }
+
+    Ok(output)
+}
+
+fn update_check(mode: CompressionMode, mut check: u32, data: &[u8]) -> u32 {
+    for chunk in data.chunks(u32::MAX as usize) {
+        check = unsafe {
+            match mode {
+                CompressionMode::Gzip => crc32(check as _, chunk.as_ptr(), chunk.len() as u32),
+                _ => adler32(check as _, chunk.as_ptr(), chunk.len() as u32),
+            }
+        } as u32;
+    }
+    check
+}
+
+fn header(mode: CompressionMode, level: i32) -> Vec<u8> {
+    match mode {
+        // No name, no timestamp, unknown OS
+        CompressionMode::Gzip => vec![0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 255],
+        CompressionMode::Zlib => {
+            let cmf = 0x78u16; // deflate with a 32K window
+            let level_flags = match level {
+                0 | 1 => 0,
+                2..=5 => 1,
+                6 | -1 => 2,
+                _ => 3,
+            };
+            let mut flg = level_flags << 6;
+            flg += 31 - (cmf * 256 + flg) % 31;
+            vec![cmf as u8, flg as u8]
+        }
+        CompressionMode::Raw | CompressionMode::Auto => vec![],
+    }
+}
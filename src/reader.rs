@@ -1,78 +1,334 @@
-use crate::pushback::PushbackReader;
+use crate::embed::read_appended_index;
+use crate::search::find_first;
+use crate::source::{RandomAccessSource, ReadSeekSource};
 use crate::types::*;
-use crate::zran::extract_data;
-use std::io::{self, Read, Seek, SeekFrom};
+use crate::zran::{before_first_point, buffer_size, count_delimiters, PointDecoder};
+use std::cmp::Ordering;
+use std::io::{self, BufRead, Read, Seek, SeekFrom};
+use std::sync::Arc;
 
-pub struct SeekableZLibReader<R: Read + Seek> {
-    reader: PushbackReader<R>,
+pub struct SeekableZLibReader<S: RandomAccessSource> {
+    source: Arc<S>, // shared with the decoder
     index: DeflateIndex,
     current_offset: u64,
-    buffer: Vec<u8>,
+    decoder: Option<PointDecoder<Arc<S>>>, // continues where the last refill stopped
+    decoded: u64,                          // uncompressed offset the decoder is at
+    buffer: Vec<u8>, // decoded data, starting history bytes before the last refill
     buffer_pos: usize,
     buffer_size: usize,
+    history: usize,     // bytes of already read data kept on refills
+    input_size: usize,  // compressed bytes read at a time
+    output_size: usize, // bytes decoded by the first refill after a seek
+    read_ahead: usize,  // bytes decoded by the refills of sequential reads
 }
 
-impl<R: Read + Seek> SeekableZLibReader<R> {
+impl<R: Read + Seek> SeekableZLibReader<ReadSeekSource<R>> {
     pub fn new(reader: R, index: DeflateIndex) -> Self {
+        Self::from_source(ReadSeekSource::new(reader), index)
+    }
+}
+
+impl<S: RandomAccessSource> SeekableZLibReader<S> {
+    pub fn from_source(source: S, index: DeflateIndex) -> Self {
         Self {
-            reader: PushbackReader::new(reader),
+            source: Arc::new(source),
             index,
             current_offset: 0,
-            buffer: vec![0; CHUNK],
+            decoder: None,
+            decoded: 0,
+            buffer: vec![0; CHUNK * 2],
             buffer_pos: 0,
             buffer_size: 0,
+            history: CHUNK,
+            input_size: CHUNK,
+            output_size: CHUNK,
+            read_ahead: CHUNK,
+        }
+    }
+
+    /// Keeps up to `bytes` of the data read last in memory, so that seeking
+    /// back into it, as parsers that peek backwards do, needs no decoding.
+    /// Defaults to 16 KiB.
+    pub fn with_history(mut self, bytes: usize) -> Self {
+        self.history = bytes;
+        self.reset_buffer();
+        self
+    }
+
+    /// Reads `bytes` of compressed data at a time. Reads of 1-4 MiB suit
+    /// fast or high latency storage better than the default of 16 KiB.
+    pub fn with_input_buffer_size(mut self, bytes: usize) -> Self {
+        self.input_size = buffer_size(bytes);
+        self
+    }
+
+    /// Decodes `bytes` after a seek before returning any data. Tiny random
+    /// reads are faster with a small output buffer, since all of it is
+    /// decoded on every seek. Defaults to 16 KiB.
+    pub fn with_output_buffer_size(mut self, bytes: usize) -> Self {
+        self.output_size = buffer_size(bytes);
+        self.reset_buffer();
+        self
+    }
+
+    /// Decodes `bytes` at a time while reading sequentially. Sequential
+    /// refills continue decoding where the last one stopped, so this only
+    /// sets how much is decoded per call. Defaults to 16 KiB.
+    pub fn with_read_ahead(mut self, bytes: usize) -> Self {
+        self.read_ahead = buffer_size(bytes);
+        self.reset_buffer();
+        self
+    }
+
+    /// Opens a gzip source that carries its own index, as appended by
+    /// `embed::append_index`.
+    pub fn open(source: S) -> io::Result<Self> {
+        match read_appended_index(&source)? {
+            Some((index, _)) => Ok(Self::from_source(source, index)),
+            None => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "No embedded index found",
+            )),
+        }
+    }
+
+    pub fn index(&self) -> &DeflateIndex {
+        &self.index
+    }
+
+    /// Seeks to the first byte of the zero based line `line`.
+    pub fn seek_to_line(&mut self, line: u64) -> io::Result<u64> {
+        let offset = self.line_offset(line)?;
+        self.seek(SeekFrom::Start(offset))
+    }
+
+    /// Returns the uncompressed offset of the first byte of the zero based
+    /// line `line`, decoding at most one span past the access point before it.
+    pub fn line_offset(&self, line: u64) -> io::Result<u64> {
+        let delimiter = self.line_delimiter()?;
+        if line > self.index.lines {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Line number past the end of the data",
+            ));
+        }
+        if line == 0 {
+            return Ok(0);
+        }
+
+        // The last access point before the delimiter ending line - 1
+        let at = self
+            .index
+            .list
+            .partition_point(|point| point.lines < line)
+            .checked_sub(1)
+            .ok_or_else(before_first_point)?;
+        let point = &self.index.list[at];
+        let mut decoder =
+            PointDecoder::with_input_size(&*self.source, &self.index, point, self.input_size)?;
+
+        let mut remaining = line - point.lines;
+        let mut offset = point.out;
+        let mut chunk = vec![0; CHUNK];
+        loop {
+            let n = decoder.read(&mut chunk)?;
+            if n == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "Line index does not match the data",
+                ));
+            }
+            for (i, _) in chunk[..n]
+                .iter()
+                .enumerate()
+                .filter(|(_, &b)| b == delimiter)
+            {
+                remaining -= 1;
+                if remaining == 0 {
+                    return Ok(offset + i as u64 + 1);
+                }
+            }
+            offset += n as u64;
         }
     }
 
+    /// Returns the zero based line number containing the uncompressed byte at
+    /// `offset`.
+    pub fn line_at_offset(&self, offset: u64) -> io::Result<u64> {
+        let delimiter = self.line_delimiter()?;
+        if offset >= self.index.length {
+            return Ok(self.index.lines);
+        }
+
+        let point = &self.index.list[self.index.locate(offset).ok_or_else(before_first_point)?];
+        let mut decoder =
+            PointDecoder::with_input_size(&*self.source, &self.index, point, self.input_size)?;
+
+        let mut line = point.lines;
+        let mut remaining = offset - point.out;
+        let mut chunk = vec![0; CHUNK];
+        while remaining > 0 {
+            let len = std::cmp::min(remaining, CHUNK as u64) as usize;
+            let n = decoder.read(&mut chunk[..len])?;
+            if n == 0 {
+                break;
+            }
+            line += count_delimiters(&chunk[..n], delimiter);
+            remaining -= n as u64;
+        }
+        Ok(line)
+    }
+
+    /// Returns the offset of the first record for which `cmp` does not
+    /// return `Less`, for data with sorted records. See `search::find_first`.
+    pub fn find_first<F: FnMut(&[u8]) -> Ordering>(&self, cmp: F) -> io::Result<Option<u64>> {
+        find_first(&*self.source, &self.index, cmp)
+    }
+
+    /// Seeks relative to the current position. Like `BufReader`, this keeps
+    /// the buffered data when the new position falls inside it.
+    pub fn seek_relative(&mut self, offset: i64) -> io::Result<()> {
+        self.seek(SeekFrom::Current(offset)).map(|_| ())
+    }
+
+    fn line_delimiter(&self) -> io::Result<u8> {
+        self.index
+            .delimiter
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Index has no line counts"))
+    }
+
     fn fill_buffer(&mut self) -> io::Result<()> {
-        self.buffer_pos = 0;
-        self.buffer_size = extract_data(
-            &mut self.reader,
-            &self.index,
-            self.current_offset,
-            &mut self.buffer,
-        )?;
+        // The buffer has been read to the current offset; keep its tail.
+        // An empty buffer means the reader was just created or seeked.
+        let len = if self.buffer_size == 0 {
+            self.output_size
+        } else {
+            self.read_ahead
+        };
+        let keep = std::cmp::min(self.history, self.buffer_size);
+        self.buffer
+            .copy_within(self.buffer_size - keep..self.buffer_size, 0);
+        self.buffer_pos = keep;
+        self.buffer_size = keep;
+
+        // Stop at the indexed length rather than decoding trailing members
+        let want = std::cmp::min(
+            len as u64,
+            self.index.length.saturating_sub(self.current_offset),
+        ) as usize;
+        if want == 0 {
+            return Ok(());
+        }
+        let result = self.decode(keep, want);
+        if result.is_err() {
+            self.decoder = None;
+        }
+        result
+    }
+
+    // Decodes up to `want` bytes at the current offset into the buffer after
+    // `keep`
+    fn decode(&mut self, keep: usize, want: usize) -> io::Result<()> {
+        self.seek_decoder(self.current_offset)?;
+        if self.decoded < self.current_offset {
+            return Ok(()); // The data ended early
+        }
+        let decoder = self.decoder.as_mut().unwrap();
+        let mut total = 0;
+        while total < want {
+            match decoder.read(&mut self.buffer[keep + total..keep + want])? {
+                0 => break,
+                n => total += n,
+            }
+        }
+        self.buffer_size += total;
+        self.decoded += total as u64;
         Ok(())
     }
+
+    // Moves the decoder to `offset`. The live decoder is kept when `offset`
+    // is ahead of it and no access point lies in between, so sequential
+    // reads decode every span only once.
+    fn seek_decoder(&mut self, offset: u64) -> io::Result<()> {
+        let point = &self.index.list[self.index.locate(offset).ok_or_else(before_first_point)?];
+        if self.decoder.is_none() || offset < self.decoded || point.out > self.decoded {
+            self.decoder = None;
+            self.decoder = Some(PointDecoder::with_input_size(
+                Arc::clone(&self.source),
+                &self.index,
+                point,
+                self.input_size,
+            )?);
+            self.decoded = point.out;
+        }
+        let decoder = self.decoder.as_mut().unwrap();
+        self.decoded += io::copy(&mut decoder.take(offset - self.decoded), &mut io::sink())?;
+        Ok(())
+    }
+
+    // Sizes the buffer for the history and the larger of the refills
+    fn reset_buffer(&mut self) {
+        let refill = std::cmp::max(self.output_size, self.read_ahead);
+        self.buffer = vec![0; self.history + refill];
+        self.buffer_pos = 0;
+        self.buffer_size = 0;
+    }
 }
 
-impl<R: Read + Seek> Read for SeekableZLibReader<R> {
+impl<S: RandomAccessSource> Read for SeekableZLibReader<S> {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let to_copy = std::cmp::min(buf.len(), available.len());
+        buf[..to_copy].copy_from_slice(&available[..to_copy]);
+        self.consume(to_copy);
+
+        Ok(to_copy)
+    }
+}
+
+impl<S: RandomAccessSource> BufRead for SeekableZLibReader<S> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
         if self.buffer_pos >= self.buffer_size {
             self.fill_buffer()?;
         }
 
-        let available = self.buffer_size - self.buffer_pos;
-        let to_copy = std::cmp::min(buf.len(), available);
-        buf[..to_copy].copy_from_slice(&self.buffer[self.buffer_pos..self.buffer_pos + to_copy]);
-        self.buffer_pos += to_copy;
-        self.current_offset += to_copy as u64;
+        Ok(&self.buffer[self.buffer_pos..self.buffer_size])
+    }
 
-        Ok(to_copy)
+    fn consume(&mut self, amt: usize) {
+        let amt = std::cmp::min(amt, self.buffer_size - self.buffer_pos);
+        self.buffer_pos += amt;
+        self.current_offset += amt as u64;
     }
 }
 
-impl<R: Read + Seek> Seek for SeekableZLibReader<R> {
+impl<S: RandomAccessSource> Seek for SeekableZLibReader<S> {
     fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
-        self.current_offset = match pos {
-            SeekFrom::Start(offset) => offset,
-            SeekFrom::End(offset) => {
-                if offset >= 0 {
-                    self.index.length
-                } else {
-                    self.index.length - (-offset) as u64
-                }
-            }
-            SeekFrom::Current(offset) => {
-                if offset >= 0 {
-                    self.current_offset + offset as u64
-                } else {
-                    self.current_offset - (-offset) as u64
-                }
-            }
-        };
-        self.buffer_pos = 0;
-        self.buffer_size = 0; // Invalidate the buffer
+        let offset = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.index.length.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.current_offset.checked_add_signed(offset),
+        }
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Invalid seek to a negative or overflowing position",
+            )
+        })?;
+
+        // Keep the buffer if the new position is inside it
+        let buffer_start = self.current_offset - self.buffer_pos as u64;
+        if offset >= buffer_start && offset <= buffer_start + self.buffer_size as u64 {
+            self.buffer_pos = (offset - buffer_start) as usize;
+        } else {
+            self.buffer_pos = 0;
+            self.buffer_size = 0; // Invalidate the buffer
+        }
+        self.current_offset = offset;
+        Ok(offset)
+    }
+
+    fn stream_position(&mut self) -> io::Result<u64> {
         Ok(self.current_offset)
     }
 }
@@ -0,0 +1,295 @@
+use std::cmp::Ordering;
+use std::io::{self, BufRead, BufReader, Read, Take};
+use std::sync::atomic::{self, AtomicUsize};
+use std::thread;
+
+use crate::source::RandomAccessSource;
+use crate::types::{DeflateIndex, CHUNK};
+use crate::zran::{before_first_point, count_delimiters, PointDecoder};
+
+/// An occurrence of the pattern found by `search`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Match {
+    pub offset: u64,       // uncompressed offset of the first matching byte
+    pub line: Option<u64>, // zero based line number, when the index counts lines
+    pub text: Vec<u8>,     // that line without its delimiter, otherwise empty
+}
+
+/// Searches the uncompressed data for `pattern` and returns every match in
+/// order of offset, overlapping matches included.
+///
+/// The data between consecutive access points is searched by `threads`
+/// workers in parallel, each decoding its own slice a chunk at a time. When
+/// the index counts lines, the slices are cut at record starts, so every
+/// match comes with its line number and the text of the line.
+pub fn search<S: RandomAccessSource + Sync + ?Sized>(
+    source: &S,
+    index: &DeflateIndex,
+    pattern: &[u8],
+    threads: usize,
+) -> io::Result<Vec<Match>> {
+    if pattern.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Empty search pattern",
+        ));
+    }
+
+    let segments = segments(index);
+    let next = AtomicUsize::new(0);
+    let mut results = thread::scope(|scope| -> io::Result<Vec<_>> {
+        let workers: Vec<_> = (0..std::cmp::max(threads, 1))
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut found = vec![];
+                    loop {
+                        let k = next.fetch_add(1, atomic::Ordering::Relaxed);
+                        let Some(&(start, end, lines)) = segments.get(k) else {
+                            return found;
+                        };
+                        found.push((k, search_segment(source, index, pattern, start, end, lines)));
+                    }
+                })
+            })
+            .collect();
+        // Join every worker before reporting one that panicked
+        let joined: Vec<_> = workers.into_iter().map(|worker| worker.join()).collect();
+        let mut results = vec![];
+        for found in joined {
+            results.extend(
+                found
+                    .map_err(|_| io::Error::new(io::ErrorKind::Other, "Search thread panicked"))?,
+            );
+        }
+        Ok(results)
+    })?;
+
+    results.sort_by_key(|&(k, _)| k);
+    let mut matches = vec![];
+    for (_, found) in results {
+        matches.extend(found?);
+    }
+    Ok(matches)
+}
+
+/// Finds the first record for which `cmp` does not return `Less` in data
+/// whose records are sorted, and returns its uncompressed offset, or None if
+/// every record compares less.
+///
+/// Records end in the delimiter of the index, or a newline if the index
+/// counts none, and are passed to `cmp` without it. The access points are
+/// binary searched, decoding only up to the first record after each probed
+/// point, so only the final span is scanned record by record.
+pub fn find_first<S, F>(source: &S, index: &DeflateIndex, mut cmp: F) -> io::Result<Option<u64>>
+where
+    S: RandomAccessSource + ?Sized,
+    F: FnMut(&[u8]) -> Ordering,
+{
+    if index.list.is_empty() {
+        return Ok(None);
+    }
+    let mut record = vec![];
+
+    // Count the access points whose first record compares less
+    let (mut lo, mut hi) = (0, index.list.len());
+    while lo < hi {
+        let mid = lo + (hi - lo) / 2;
+        let less = match Records::at(source, index, mid)?.next(&mut record)? {
+            Some(_) => cmp(&record) == Ordering::Less,
+            None => false,
+        };
+        if less {
+            lo = mid + 1;
+        } else {
+            hi = mid;
+        }
+    }
+
+    // The first record that does not compare less comes after the last such
+    // point, and no later than the first record of the next one.
+    let mut records = Records::at(source, index, lo.saturating_sub(1))?;
+    while let Some(offset) = records.next(&mut record)? {
+        if cmp(&record) != Ordering::Less {
+            return Ok(Some(offset));
+        }
+    }
+    Ok(None)
+}
+
+// Reads the records following an access point
+struct Records<'a, S: RandomAccessSource + ?Sized> {
+    reader: BufReader<Take<PointDecoder<&'a S>>>,
+    offset: u64,
+    delimiter: u8,
+}
+
+impl<'a, S: RandomAccessSource + ?Sized> Records<'a, S> {
+    // Starts at the first record beginning at or after point `k`
+    fn at(source: &'a S, index: &DeflateIndex, k: usize) -> io::Result<Self> {
+        let point = &index.list[k];
+        let decoder = PointDecoder::new(source, index, point)?;
+        let mut records = Self {
+            reader: BufReader::new(decoder.take(index.length - point.out)),
+            offset: point.out,
+            delimiter: index.delimiter.unwrap_or(b'\n'),
+        };
+
+        if k > 0 {
+            match index.delimiter {
+                Some(_) => {
+                    let skip = point.record - point.out;
+                    io::copy(&mut (&mut records.reader).take(skip), &mut io::sink())?;
+                    records.offset = point.record;
+                }
+                // Drop what may be the tail of a record
+                None => {
+                    records.next(&mut vec![])?;
+                }
+            }
+        }
+        Ok(records)
+    }
+
+    // Reads the next record without its delimiter and returns its offset
+    fn next(&mut self, record: &mut Vec<u8>) -> io::Result<Option<u64>> {
+        record.clear();
+        let n = self.reader.read_until(self.delimiter, record)?;
+        if n == 0 {
+            return Ok(None);
+        }
+        if record.last() == Some(&self.delimiter) {
+            record.pop();
+        }
+
+        let offset = self.offset;
+        self.offset += n as u64;
+        Ok(Some(offset))
+    }
+}
+
+// Cuts the data into one slice per access point, as (start, end, lines before
+// start). With a delimiter the slices start at the record after each point.
+fn segments(index: &DeflateIndex) -> Vec<(u64, u64, Option<u64>)> {
+    let start = |k: usize| -> (u64, Option<u64>) {
+        let point = &index.list[k];
+        match index.delimiter {
+            // A record start past the point follows the point's first delimiter
+            Some(_) => (
+                point.record,
+                Some(point.lines + u64::from(point.record > point.out)),
+            ),
+            None => (point.out, None),
+        }
+    };
+
+    (0..index.list.len())
+        .map(|k| {
+            let (begin, lines) = start(k);
+            let end = if k + 1 < index.list.len() {
+                start(k + 1).0
+            } else {
+                index.length
+            };
+            (begin, std::cmp::min(end, index.length), lines)
+        })
+        .filter(|&(begin, end, _)| begin < end)
+        .collect()
+}
+
+// Finds the matches starting in [start, end), decoding far enough past the
+// end to see matches that straddle it. The data is decoded a chunk at a
+// time, keeping what a match or its line may still need.
+fn search_segment<S: RandomAccessSource + ?Sized>(
+    source: &S,
+    index: &DeflateIndex,
+    pattern: &[u8],
+    start: u64,
+    end: u64,
+    lines: Option<u64>,
+) -> io::Result<Vec<Match>> {
+    let want = std::cmp::min(end + pattern.len() as u64 - 1, index.length);
+    let point = &index.list[index.locate(start).ok_or_else(before_first_point)?];
+    let mut decoder = PointDecoder::new(source, index, point)?;
+    io::copy(&mut (&mut decoder).take(start - point.out), &mut io::sink())?;
+    let mut decoder = decoder.take(want - start);
+
+    let mut matches = vec![];
+    let mut line = lines;
+    let mut data = vec![]; // decoded data from offset base on
+    let mut base = start;
+    let mut counted = 0; // data before this has been counted into line
+    let mut at = 0; // no match starts before this
+    let mut chunk = vec![0; CHUNK];
+    loop {
+        let n = decoder.read(&mut chunk)?;
+        data.extend_from_slice(&chunk[..n]);
+        let done = n == 0;
+
+        while let Some(i) = find(&data[at..], pattern) {
+            let pos = at + i;
+            if base + pos as u64 >= end {
+                return Ok(matches);
+            }
+
+            let mut text = vec![];
+            if let (Some(delimiter), Some(line)) = (index.delimiter, line.as_mut()) {
+                // Wait for the end of the line
+                let finish = data[pos..].iter().position(|&b| b == delimiter);
+                if finish.is_none() && !done {
+                    break;
+                }
+                *line += count_delimiters(&data[counted..pos], delimiter);
+                counted = pos;
+                let begin = data[..pos]
+                    .iter()
+                    .rposition(|&b| b == delimiter)
+                    .map_or(0, |i| i + 1);
+                let finish = finish.map_or(data.len(), |i| pos + i);
+                text = data[begin..finish].to_vec();
+            }
+
+            matches.push(Match {
+                offset: base + pos as u64,
+                line,
+                text,
+            });
+            at = pos + 1;
+        }
+        if done {
+            return Ok(matches);
+        }
+
+        // Keep the tail a match may start in, from the start of its line
+        let mut keep = std::cmp::max(at, data.len().saturating_sub(pattern.len() - 1));
+        if let Some(delimiter) = index.delimiter {
+            keep = data[..keep]
+                .iter()
+                .rposition(|&b| b == delimiter)
+                .map_or(0, |i| i + 1);
+            if let Some(line) = line.as_mut() {
+                if counted < keep {
+                    *line += count_delimiters(&data[counted..keep], delimiter);
+                    counted = keep;
+                }
+            }
+        }
+        data.drain(..keep);
+        base += keep as u64;
+        at = at.saturating_sub(keep);
+        counted -= std::cmp::min(counted, keep);
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    let mut at = 0;
+    while at + needle.len() <= haystack.len() {
+        let i = haystack[at..=haystack.len() - needle.len()]
+            .iter()
+            .position(|&b| b == needle[0])?;
+        if haystack[at + i..].starts_with(needle) {
+            return Some(at + i);
+        }
+        at += i + 1;
+    }
+    None
+}
@@ -0,0 +1,337 @@
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// A source of compressed bytes that supports positioned reads.
+///
+/// Unlike `Read + Seek`, a `RandomAccessSource` is read through a shared
+/// reference, so ranged backends (files, memory maps, HTTP range requests)
+/// only fetch the bytes that are actually needed between two access points.
+pub trait RandomAccessSource {
+    /// Total length of the source in bytes.
+    fn len(&self) -> io::Result<u64>;
+
+    /// Returns true if the source contains no bytes.
+    fn is_empty(&self) -> io::Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Reads up to `buf.len()` bytes starting at `offset`. Returns the number
+    /// of bytes read, which is zero only at or past the end of the source.
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<usize>;
+
+    /// Fills `buf` from `offset`, failing with `UnexpectedEof` if the source
+    /// ends first.
+    fn read_exact_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        let mut filled = 0;
+        while filled < buf.len() {
+            match self.read_at(offset + filled as u64, &mut buf[filled..])? {
+                0 => return Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+                n => filled += n,
+            }
+        }
+        Ok(())
+    }
+
+    /// Identifies the current contents of the source, such as the ETag of a
+    /// remote object, so that an index built from other contents is caught.
+    /// None when the source cannot tell.
+    fn fingerprint(&self) -> Option<String> {
+        None
+    }
+}
+
+impl<S: RandomAccessSource + ?Sized> RandomAccessSource for &S {
+    fn len(&self) -> io::Result<u64> {
+        (**self).len()
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        (**self).read_at(offset, buf)
+    }
+
+    fn fingerprint(&self) -> Option<String> {
+        (**self).fingerprint()
+    }
+}
+
+impl<S: RandomAccessSource + ?Sized> RandomAccessSource for Box<S> {
+    fn len(&self) -> io::Result<u64> {
+        (**self).len()
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        (**self).read_at(offset, buf)
+    }
+
+    fn fingerprint(&self) -> Option<String> {
+        (**self).fingerprint()
+    }
+}
+
+impl<S: RandomAccessSource + ?Sized> RandomAccessSource for Arc<S> {
+    fn len(&self) -> io::Result<u64> {
+        (**self).len()
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        (**self).read_at(offset, buf)
+    }
+
+    fn fingerprint(&self) -> Option<String> {
+        (**self).fingerprint()
+    }
+}
+
+impl RandomAccessSource for [u8] {
+    fn len(&self) -> io::Result<u64> {
+        Ok(<[u8]>::len(self) as u64)
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let start = std::cmp::min(offset, <[u8]>::len(self) as u64) as usize;
+        let n = std::cmp::min(buf.len(), <[u8]>::len(self) - start);
+        buf[..n].copy_from_slice(&self[start..start + n]);
+        Ok(n)
+    }
+}
+
+impl RandomAccessSource for Vec<u8> {
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.as_slice().len() as u64)
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        self.as_slice().read_at(offset, buf)
+    }
+}
+
+/// In-memory source over anything that derefs to bytes, e.g. a memory map.
+pub struct MemorySource<T: AsRef<[u8]>>(pub T);
+
+impl<T: AsRef<[u8]>> RandomAccessSource for MemorySource<T> {
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.0.as_ref().len() as u64)
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.as_ref().read_at(offset, buf)
+    }
+}
+
+/// File source using positioned reads, so it can be shared between readers.
+pub struct FileSource {
+    file: File,
+    len: u64,
+}
+
+impl FileSource {
+    pub fn new(file: File) -> io::Result<Self> {
+        let len = file.metadata()?.len();
+        Ok(Self { file, len })
+    }
+
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::new(File::open(path)?)
+    }
+}
+
+impl RandomAccessSource for FileSource {
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.len)
+    }
+
+    #[cfg(unix)]
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        std::os::unix::fs::FileExt::read_at(&self.file, buf, offset)
+    }
+
+    #[cfg(windows)]
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        std::os::windows::fs::FileExt::seek_read(&self.file, buf, offset)
+    }
+}
+
+/// The bytes `start..start + len` of another source, e.g. one entry of an
+/// archive.
+pub struct RangeSource<S: RandomAccessSource> {
+    inner: S,
+    start: u64,
+    len: u64,
+}
+
+impl<S: RandomAccessSource> RangeSource<S> {
+    pub fn new(inner: S, start: u64, len: u64) -> Self {
+        Self { inner, start, len }
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S: RandomAccessSource> RandomAccessSource for RangeSource<S> {
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.len)
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        if offset >= self.len {
+            return Ok(0);
+        }
+        let len = std::cmp::min(buf.len() as u64, self.len - offset) as usize;
+        self.inner.read_at(self.start + offset, &mut buf[..len])
+    }
+
+    fn fingerprint(&self) -> Option<String> {
+        self.inner.fingerprint()
+    }
+}
+
+/// Adapts any `Read + Seek` into a source. Reads are serialized by a lock
+/// because every positioned read needs a seek on the inner reader.
+pub struct ReadSeekSource<R: Read + Seek> {
+    inner: Mutex<R>,
+}
+
+impl<R: Read + Seek> ReadSeekSource<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner: Mutex::new(inner),
+        }
+    }
+
+    pub fn into_inner(self) -> R {
+        match self.inner.into_inner() {
+            Ok(inner) => inner,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    fn lock(&self) -> io::Result<std::sync::MutexGuard<'_, R>> {
+        self.inner
+            .lock()
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "Source lock poisoned"))
+    }
+}
+
+impl<R: Read + Seek> RandomAccessSource for ReadSeekSource<R> {
+    fn len(&self) -> io::Result<u64> {
+        self.lock()?.seek(SeekFrom::End(0))
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let mut inner = self.lock()?;
+        inner.seek(SeekFrom::Start(offset))?;
+        inner.read(buf)
+    }
+}
+
+/// Sequential `Read + Seek` cursor over a `RandomAccessSource`.
+pub struct SourceReader<S: RandomAccessSource> {
+    source: S,
+    pos: u64,
+}
+
+impl<S: RandomAccessSource> SourceReader<S> {
+    pub fn new(source: S) -> Self {
+        Self { source, pos: 0 }
+    }
+
+    pub fn position(&self) -> u64 {
+        self.pos
+    }
+
+    pub fn into_inner(self) -> S {
+        self.source
+    }
+
+    pub(crate) fn is_eof(&self) -> io::Result<bool> {
+        Ok(self.pos >= self.source.len()?)
+    }
+}
+
+impl<S: RandomAccessSource> Read for SourceReader<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.source.read_at(self.pos, buf)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl<S: RandomAccessSource> Seek for SourceReader<S> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, offset) = match pos {
+            SeekFrom::Start(offset) => {
+                self.pos = offset;
+                return Ok(offset);
+            }
+            SeekFrom::End(offset) => (self.source.len()?, offset),
+            SeekFrom::Current(offset) => (self.pos, offset),
+        };
+        self.pos = base.checked_add_signed(offset).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Invalid seek to a negative position",
+            )
+        })?;
+        Ok(self.pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_slice_read_at() {
+        let data = b"Hello, world!".to_vec();
+        let mut buffer = [0; 5];
+        assert_eq!(data.read_at(7, &mut buffer).unwrap(), 5);
+        assert_eq!(&buffer, b"world");
+        assert_eq!(data.read_at(11, &mut buffer).unwrap(), 2);
+        assert_eq!(data.read_at(20, &mut buffer).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_range_source() {
+        let source = RangeSource::new(&b"Hello, world!"[..], 7, 5);
+        assert_eq!(source.len().unwrap(), 5);
+
+        let mut buffer = [0; 8];
+        assert_eq!(source.read_at(0, &mut buffer).unwrap(), 5);
+        assert_eq!(&buffer[..5], b"world");
+        assert_eq!(source.read_at(3, &mut buffer).unwrap(), 2);
+        assert_eq!(source.read_at(5, &mut buffer).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_read_seek_source() {
+        let source = ReadSeekSource::new(Cursor::new(b"Hello, world!"));
+        assert_eq!(source.len().unwrap(), 13);
+
+        let mut buffer = [0; 5];
+        source.read_at(7, &mut buffer).unwrap();
+        assert_eq!(&buffer, b"world");
+        source.read_at(0, &mut buffer).unwrap();
+        assert_eq!(&buffer, b"Hello");
+    }
+
+    #[test]
+    fn test_source_reader() {
+        let data = b"Hello, world!";
+        let mut reader = SourceReader::new(&data[..]);
+
+        reader.seek(SeekFrom::End(-6)).unwrap();
+        let mut buffer = [0; 5];
+        reader.read_exact(&mut buffer).unwrap();
+        assert_eq!(&buffer, b"world");
+        assert!(!reader.is_eof().unwrap());
+
+        reader.seek(SeekFrom::Current(1)).unwrap();
+        assert!(reader.is_eof().unwrap());
+        assert!(reader.seek(SeekFrom::Current(-20)).is_err());
+    }
+}
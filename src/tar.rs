@@ -0,0 +1,297 @@
+// Random access to the entries of a .tar.gz archive. The tar headers are
+// parsed from the uncompressed data while the index is built, so an entry
+// can be read by seeking straight to its data.
+
+use std::io::{self, Read, Seek, SeekFrom};
+
+use crate::reader::SeekableZLibReader;
+use crate::source::{RandomAccessSource, SourceReader};
+use crate::types::DeflateIndex;
+use crate::zran::IndexBuilder;
+
+const BLOCK: usize = 512;
+const MAX_META: u64 = 1 << 20; // bytes of a long name or pax header kept
+
+/// An entry of a tar archive.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TarEntry {
+    pub name: String,
+    pub size: u64,
+    pub mode: u32,
+    pub kind: u8,    // tar typeflag, b'0' for regular files
+    pub offset: u64, // uncompressed offset of the entry data
+}
+
+/// Builds the index of a compressed tar archive and lists its entries.
+pub fn build_tar_index<R: Read + Seek>(
+    reader: &mut R,
+    builder: &IndexBuilder,
+) -> io::Result<(DeflateIndex, Vec<TarEntry>)> {
+    let mut parser = TarParser::default();
+    let index = builder.build_observed(reader, &mut |data| parser.feed(data))?;
+    match parser.error {
+        Some(msg) => Err(io::Error::new(io::ErrorKind::InvalidData, msg)),
+        None => Ok((index, parser.entries)),
+    }
+}
+
+/// A compressed tar archive with an index of its entries.
+pub struct TarArchive<S: RandomAccessSource> {
+    reader: SeekableZLibReader<S>,
+    entries: Vec<TarEntry>,
+}
+
+impl<S: RandomAccessSource> TarArchive<S> {
+    pub fn new(source: S, index: DeflateIndex, entries: Vec<TarEntry>) -> Self {
+        Self {
+            reader: SeekableZLibReader::from_source(source, index),
+            entries,
+        }
+    }
+
+    /// Indexes the archive in `source`.
+    pub fn open(source: S, builder: &IndexBuilder) -> io::Result<Self> {
+        let mut reader = SourceReader::new(&source);
+        let (index, entries) = build_tar_index(&mut reader, builder)?;
+        Ok(Self::new(source, index, entries))
+    }
+
+    pub fn entries(&self) -> &[TarEntry] {
+        &self.entries
+    }
+
+    pub fn index(&self) -> &DeflateIndex {
+        self.reader.index()
+    }
+
+    /// Returns the entry called `name`. If the archive holds several, the
+    /// last one wins, as when extracting.
+    pub fn entry(&self, name: &str) -> Option<&TarEntry> {
+        self.entries.iter().rev().find(|entry| entry.name == name)
+    }
+
+    /// Opens the data of the entry called `name` for reading.
+    pub fn open_entry(&mut self, name: &str) -> io::Result<EntryReader<'_, S>> {
+        let entry = self.entry(name).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No entry {} in the archive", name),
+            )
+        })?;
+        let (start, size) = (entry.offset, entry.size);
+
+        self.reader.seek(SeekFrom::Start(start))?;
+        Ok(EntryReader {
+            reader: &mut self.reader,
+            start,
+            size,
+            position: 0,
+        })
+    }
+}
+
+/// Reader over the data of one tar entry.
+pub struct EntryReader<'a, S: RandomAccessSource> {
+    reader: &'a mut SeekableZLibReader<S>,
+    start: u64,
+    size: u64,
+    position: u64, // the inner reader is at start + position
+}
+
+impl<S: RandomAccessSource> Read for EntryReader<'_, S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = self.size.saturating_sub(self.position);
+        let len = std::cmp::min(buf.len() as u64, remaining) as usize;
+        let n = self.reader.read(&mut buf[..len])?;
+        self.position += n as u64;
+        Ok(n)
+    }
+}
+
+impl<S: RandomAccessSource> Seek for EntryReader<'_, S> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.size.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        }
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Invalid seek to a negative or overflowing position",
+            )
+        })?;
+
+        self.reader.seek(SeekFrom::Start(self.start + position))?;
+        self.position = position;
+        Ok(position)
+    }
+}
+
+// Streaming parser for tar headers
+#[derive(Default)]
+struct TarParser {
+    header: Vec<u8>,    // partial header block
+    skip: u64,          // entry data and padding left to skip
+    meta: Option<u8>,   // typeflag of a GNU long name or pax header being read
+    meta_data: Vec<u8>, // its contents
+    meta_len: usize,
+    long_name: Option<String>, // applies to the next entry
+    pax_size: Option<u64>,
+    offset: u64, // uncompressed offset of the next byte fed
+    entries: Vec<TarEntry>,
+    done: bool,
+    error: Option<String>,
+}
+
+impl TarParser {
+    fn feed(&mut self, mut data: &[u8]) {
+        while !data.is_empty() && !self.done {
+            let n = if self.skip > 0 {
+                let n = std::cmp::min(self.skip, data.len() as u64) as usize;
+                if self.meta.is_some() {
+                    let want = self.meta_len - self.meta_data.len();
+                    self.meta_data
+                        .extend_from_slice(&data[..std::cmp::min(n, want)]);
+                }
+                self.skip -= n as u64;
+                if self.skip == 0 {
+                    self.finish_meta();
+                }
+                n
+            } else {
+                let n = std::cmp::min(BLOCK - self.header.len(), data.len());
+                self.header.extend_from_slice(&data[..n]);
+                if self.header.len() == BLOCK {
+                    let header = std::mem::take(&mut self.header);
+                    self.parse_header(&header, self.offset + n as u64);
+                }
+                n
+            };
+            self.offset += n as u64;
+            data = &data[n..];
+        }
+    }
+
+    // Handles a complete header block; `data` is the offset right after it
+    fn parse_header(&mut self, header: &[u8], data: u64) {
+        if header.iter().all(|&b| b == 0) {
+            // End of archive
+            self.done = true;
+            return;
+        }
+
+        let checksum: u64 = header
+            .iter()
+            .enumerate()
+            .map(|(i, &b)| {
+                if (148..156).contains(&i) {
+                    32
+                } else {
+                    b as u64
+                }
+            })
+            .sum();
+        let size = parse_number(&header[124..136]);
+        if parse_number(&header[148..156]) != Some(checksum) || size.is_none() {
+            self.error = Some(format!(
+                "Invalid tar header at offset {}",
+                data - BLOCK as u64
+            ));
+            self.done = true;
+            return;
+        }
+
+        let kind = header[156];
+        let size = size.unwrap();
+        self.skip = size.div_ceil(BLOCK as u64) * BLOCK as u64;
+
+        match kind {
+            // GNU long name and pax extended header of the next entry
+            b'L' | b'x' => {
+                if size > MAX_META {
+                    self.error = Some(format!(
+                        "Tar metadata of {} bytes at offset {} is too large",
+                        size,
+                        data - BLOCK as u64
+                    ));
+                    self.done = true;
+                    return;
+                }
+                self.meta = Some(kind);
+                self.meta_len = size as usize;
+                self.meta_data.clear();
+                if self.skip == 0 {
+                    self.finish_meta();
+                }
+            }
+            // Skipped metadata: GNU long link name, global pax header,
+            // Solaris extended header, GNU volume label and old GNU names
+            b'K' | b'g' | b'X' | b'V' | b'N' => {}
+            _ => {
+                // A pax size applies to the entry, not to other metadata
+                let size = self.pax_size.take().unwrap_or(size);
+                self.skip = size.div_ceil(BLOCK as u64) * BLOCK as u64;
+                let mut name = field(&header[..100]);
+                if &header[257..262] == b"ustar" && header[345] != 0 {
+                    name = format!("{}/{}", field(&header[345..500]), name);
+                }
+                self.entries.push(TarEntry {
+                    name: self.long_name.take().unwrap_or(name),
+                    size,
+                    mode: parse_number(&header[100..108]).unwrap_or(0) as u32,
+                    kind,
+                    offset: data,
+                });
+            }
+        }
+    }
+
+    fn finish_meta(&mut self) {
+        match self.meta.take() {
+            Some(b'L') => self.long_name = Some(field(&self.meta_data)),
+            Some(b'x') => {
+                // Records of the form "<length> <key>=<value>\n"
+                let mut rest = &self.meta_data[..];
+                while let Some(space) = rest.iter().position(|&b| b == b' ') {
+                    let len = match field(&rest[..space]).parse::<usize>() {
+                        Ok(len) if len > space + 1 && len <= rest.len() => len,
+                        _ => break,
+                    };
+                    let record = String::from_utf8_lossy(&rest[space + 1..len - 1]).into_owned();
+                    match record.split_once('=') {
+                        Some(("path", path)) => self.long_name = Some(path.to_string()),
+                        Some(("size", size)) => self.pax_size = size.parse().ok(),
+                        _ => {}
+                    }
+                    rest = &rest[len..];
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+// A NUL terminated string field
+fn field(data: &[u8]) -> String {
+    let end = data.iter().position(|&b| b == 0).unwrap_or(data.len());
+    String::from_utf8_lossy(&data[..end]).into_owned()
+}
+
+// An octal number, or a base-256 one as GNU tar writes for large values
+fn parse_number(data: &[u8]) -> Option<u64> {
+    if data[0] & 0x80 != 0 {
+        return Some(
+            data[1..]
+                .iter()
+                .fold((data[0] & 0x7f) as u64, |n, &b| (n << 8) | b as u64),
+        );
+    }
+
+    let text = field(data);
+    let text = text.trim_matches(|c: char| c == ' ');
+    if text.is_empty() {
+        return Some(0);
+    }
+    u64::from_str_radix(text, 8).ok()
+}
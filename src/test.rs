@@ -1,12 +1,27 @@
-use std::io::{self, Cursor, Read, Seek, SeekFrom};
+use libz_rs_sys::{deflate, deflateSetDictionary, Z_FINISH, Z_OK, Z_STREAM_END};
+use proptest::prelude::*;
+use std::cell::Cell;
+use std::io::{self, BufRead, Cursor, Read, Seek, SeekFrom, Write};
 use zlib_rs::deflate::compress_slice;
 use zlib_rs::deflate::DeflateConfig;
 use zlib_rs::ReturnCode;
 
+use crate::blocks::{scan_blocks, BlockType};
+use crate::concat::ConcatSeekableReader;
+use crate::embed::{append_index, read_appended_index};
+use crate::parallel::{ParallelGzWriter, ParallelOptions};
 use crate::reader::SeekableZLibReader;
+use crate::search::{find_first, search};
+use crate::source::RandomAccessSource;
+use crate::tar::{build_tar_index, TarArchive};
 use crate::types::CompressionMode::*;
-use crate::types::CHUNK;
-use crate::zran::build_index;
+use crate::types::{CompressionMode, DeflateIndex, CHUNK};
+use crate::writer::{Deflater, SeekableGzWriter, SplitMode};
+use crate::zip::ZipArchive;
+use crate::zran::{
+    build_index, count_delimiters, densify, detect_mode, extract_data, new_z_stream, IndexBuilder,
+    TrailingData,
+};
 
 // Fills the provided buffer with pseudorandom bytes based on the given seed
 // Duplicates bytes by `step` in a row
@@ -32,13 +47,35 @@ fn create_data(seed: u64) -> io::Result<Vec<u8>> {
     Ok(data)
 }
 
+// Creates newline separated text records of varying length
+fn create_lines(count: usize) -> Vec<u8> {
+    let mut data = vec![];
+    for i in 0..count {
+        data.extend_from_slice(format!("line {} {}\n", i, "x".repeat(i % 37)).as_bytes());
+    }
+    data
+}
+
+// Creates lines of random hex, which compress to short deflate blocks that
+// end all over the window
+fn create_hex_lines(seed: u64) -> Vec<u8> {
+    let mut random = vec![0; 300_000];
+    prng_bytes(seed, &mut random, 1);
+    let mut data = vec![];
+    for (i, chunk) in random.chunks(30).enumerate() {
+        let hex: String = chunk.iter().map(|b| format!("{:02x}", b)).collect();
+        data.extend_from_slice(format!("{} {}\n", i, hex).as_bytes());
+    }
+    data
+}
+
 fn compress(data: &[u8], window_bits: i32) -> io::Result<Vec<u8>> {
     let config = DeflateConfig {
         window_bits,
         ..DeflateConfig::default()
     };
 
-    let mut output = vec![0u8; 80000];
+    let mut output = vec![0u8; data.len() + 1024];
     // Compress the data
     let (compressed_data, return_code) = compress_slice(&mut output, &data, config);
     assert_eq!(return_code, ReturnCode::Ok);
@@ -50,6 +87,23 @@ fn compress(data: &[u8], window_bits: i32) -> io::Result<Vec<u8>> {
     Ok(output)
 }
 
+// Compresses with a preset dictionary, which a zlib header declares (FDICT)
+fn compress_with_dictionary(data: &[u8], dictionary: &[u8], window_bits: i32) -> Vec<u8> {
+    let mut stream = Deflater::new(6, window_bits).unwrap();
+    let mut output = vec![0u8; data.len() + 1024];
+    unsafe {
+        let ret = deflateSetDictionary(&mut *stream, dictionary.as_ptr(), dictionary.len() as u32);
+        assert_eq!(ret, Z_OK);
+        stream.next_in = data.as_ptr() as *mut u8;
+        stream.avail_in = data.len() as u32;
+        stream.next_out = output.as_mut_ptr();
+        stream.avail_out = output.len() as u32;
+        assert_eq!(deflate(&mut *stream, Z_FINISH), Z_STREAM_END);
+    }
+    output.truncate(stream.total_out as usize);
+    output
+}
+
 #[test]
 fn test_seekable_raw_reader() -> io::Result<()> {
     let data = create_data(12345)?;
@@ -124,3 +178,1545 @@ pub fn test_seekable_zlib_reader_gz() -> io::Result<()> {
     let window_bits = Gzip as i32;
     test_seekable_zlib_reader(span, window_bits)
 }
+
+// Source that records the lowest offset and the number of bytes fetched
+struct CountingSource {
+    data: Vec<u8>,
+    lowest: Cell<u64>,
+    fetched: Cell<u64>,
+}
+
+impl CountingSource {
+    fn new(data: Vec<u8>) -> Self {
+        Self {
+            data,
+            lowest: Cell::new(u64::MAX),
+            fetched: Cell::new(0),
+        }
+    }
+}
+
+impl RandomAccessSource for CountingSource {
+    fn len(&self) -> io::Result<u64> {
+        RandomAccessSource::len(&self.data)
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.data.read_at(offset, buf)?;
+        self.lowest.set(self.lowest.get().min(offset));
+        self.fetched.set(self.fetched.get() + n as u64);
+        Ok(n)
+    }
+}
+
+#[test]
+pub fn test_source_reads_only_needed_range() -> io::Result<()> {
+    let data = create_data(12345)?;
+    let compressed_data = compress(&data, Gzip as i32)?;
+    let index = build_index(&mut Cursor::new(&compressed_data), CHUNK as u64)?;
+
+    let off = data.len() - 100;
+    let point = index
+        .list
+        .iter()
+        .rev()
+        .find(|p| p.out <= off as u64)
+        .unwrap();
+    let inn = point.inn;
+
+    let source = CountingSource::new(compressed_data.clone());
+    let mut reader = SeekableZLibReader::from_source(&source, index);
+    reader.seek(SeekFrom::Start(off as u64))?;
+    let mut buffer = vec![0; 100];
+    reader.read_exact(&mut buffer)?;
+
+    assert_eq!(buffer, &data[off..]);
+    assert!(source.lowest.get() >= inn - 1);
+    assert!(source.fetched.get() < compressed_data.len() as u64);
+
+    Ok(())
+}
+
+#[test]
+pub fn test_extract_at_every_point() -> io::Result<()> {
+    let data = create_data(54321)?;
+    for window_bits in [Raw as i32, Zlib as i32, Gzip as i32] {
+        let compressed_data = compress(&data, window_bits)?;
+        let index = build_index(&mut Cursor::new(&compressed_data), 4096)?;
+        assert!(index.list.len() > 2);
+
+        for point in &index.list {
+            let mut buffer = vec![0; 64];
+            let n = extract_data(&compressed_data, &index, point.out, &mut buffer)?;
+            let end = point.out as usize + n;
+            assert_eq!(&buffer[..n], &data[point.out as usize..end]);
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+pub fn test_buf_read_lines() -> io::Result<()> {
+    let data = create_lines(20000);
+    let compressed_data = compress(&data, Gzip as i32)?;
+    let index = build_index(&mut Cursor::new(&compressed_data), CHUNK as u64)?;
+    let mut reader = SeekableZLibReader::new(Cursor::new(compressed_data), index);
+
+    let expected: Vec<&str> = std::str::from_utf8(&data).unwrap().lines().collect();
+    let lines = (&mut reader).lines().collect::<io::Result<Vec<String>>>()?;
+    assert_eq!(lines, expected);
+
+    // Resume line reading from the middle of a record
+    let off = data.len() / 2;
+    reader.seek(SeekFrom::Start(off as u64))?;
+    let mut partial = vec![];
+    reader.read_until(b'\n', &mut partial)?;
+    let end = off + data[off..].iter().position(|&b| b == b'\n').unwrap() + 1;
+    assert_eq!(partial, &data[off..end]);
+
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    assert_eq!(
+        line.trim_end(),
+        std::str::from_utf8(&data[end..])
+            .unwrap()
+            .lines()
+            .next()
+            .unwrap()
+    );
+
+    Ok(())
+}
+
+#[test]
+pub fn test_line_index() -> io::Result<()> {
+    let data = create_lines(50000);
+    let compressed_data = compress(&data, Zlib as i32)?;
+    let index = IndexBuilder::new()
+        .span(CHUNK as u64)
+        .count_lines()
+        .build(&mut Cursor::new(&compressed_data))?;
+    assert_eq!(index.lines, 50000);
+    assert!(index.list.len() > 4);
+
+    let starts: Vec<usize> = std::iter::once(0)
+        .chain(
+            data.iter()
+                .enumerate()
+                .filter(|(_, &b)| b == b'\n')
+                .map(|(i, _)| i + 1),
+        )
+        .collect();
+    for point in &index.list {
+        assert_eq!(
+            point.lines,
+            starts.partition_point(|&s| s <= point.out as usize) as u64 - 1
+        );
+    }
+
+    let mut reader = SeekableZLibReader::new(Cursor::new(compressed_data), index);
+    for line in [0, 1, 999, 12345, 31337, 49999, 50000] {
+        let offset = reader.seek_to_line(line)?;
+        assert_eq!(offset, starts[line as usize] as u64);
+        assert_eq!(reader.line_at_offset(offset)?, line);
+        if offset > 0 {
+            assert_eq!(reader.line_at_offset(offset - 1)?, line - 1);
+        }
+    }
+
+    reader.seek_to_line(4242)?;
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    assert!(line.starts_with("line 4242 "));
+    assert!(reader.seek_to_line(50001).is_err());
+
+    Ok(())
+}
+
+#[test]
+pub fn test_read_across_gzip_members() -> io::Result<()> {
+    let first = create_data(1)?;
+    let second = create_lines(10000);
+    let mut compressed_data = compress(&first, Gzip as i32)?;
+    compressed_data.extend(compress(&second, Gzip as i32)?);
+
+    // A single access point forces decoding through the member boundary
+    let index = build_index(&mut Cursor::new(&compressed_data), u64::MAX)?;
+    assert_eq!(index.list.len(), 1);
+    assert_eq!(index.length, (first.len() + second.len()) as u64);
+
+    let mut reader = SeekableZLibReader::new(Cursor::new(compressed_data), index);
+    reader.seek(SeekFrom::Start(first.len() as u64 - 10))?;
+    let mut buffer = vec![0; 20];
+    reader.read_exact(&mut buffer)?;
+    assert_eq!(&buffer[..10], &first[first.len() - 10..]);
+    assert_eq!(&buffer[10..], &second[..10]);
+
+    Ok(())
+}
+
+#[test]
+pub fn test_record_shards() -> io::Result<()> {
+    let data = create_lines(40000);
+    let compressed_data = compress(&data, Gzip as i32)?;
+    let index = IndexBuilder::new()
+        .span(8192)
+        .count_lines()
+        .record_aligned()
+        .build(&mut Cursor::new(&compressed_data))?;
+
+    for point in &index.list {
+        let record = point.record as usize;
+        assert!(record >= point.out as usize);
+        assert!(record == 0 || data[record - 1] == b'\n');
+        if record > point.out as usize {
+            assert!(!data[point.out as usize..record - 1].contains(&b'\n'));
+        }
+    }
+
+    let shards = index.record_shards(4);
+    assert_eq!(shards.len(), 4);
+    assert_eq!(shards[0].start, 0);
+    assert_eq!(shards[3].end, data.len() as u64);
+
+    let mut joined = vec![];
+    for shard in &shards {
+        assert!(index.list[shard.point].out <= shard.start);
+        assert!(shard.start == 0 || data[shard.start as usize - 1] == b'\n');
+
+        let mut buffer = vec![0; (shard.end - shard.start) as usize];
+        let n = extract_data(&compressed_data, &index, shard.start, &mut buffer)?;
+        assert_eq!(n, buffer.len());
+        joined.extend(buffer);
+    }
+    assert_eq!(joined, data);
+
+    // Without a delimiter there are no records to align to
+    let unaligned = build_index(&mut Cursor::new(&compressed_data), 8192)?;
+    let index = IndexBuilder::new()
+        .span(8192)
+        .record_aligned()
+        .build(&mut Cursor::new(&compressed_data))?;
+    assert!(index.list.len() > 1);
+    assert_eq!(index.list.len(), unaligned.list.len());
+
+    Ok(())
+}
+
+fn test_seekable_gz_writer(split: SplitMode) -> io::Result<()> {
+    let data = create_lines(30000);
+    let span = 100_000;
+
+    let mut writer = SeekableGzWriter::with_options(vec![], span, 6, split)?;
+    for chunk in data.chunks(7777) {
+        writer.write_all(chunk)?;
+    }
+    let (compressed_data, index) = writer.finish()?;
+
+    assert_eq!(index.length, data.len() as u64);
+    assert_eq!(
+        index.list.len(),
+        (data.len() as u64).div_ceil(span) as usize
+    );
+    for (i, point) in index.list.iter().enumerate() {
+        assert_eq!(point.out, i as u64 * span);
+        assert_eq!(point.bits, 0);
+        assert!(point.window.is_empty());
+    }
+
+    // Any gzip reader sees the original data
+    let rebuilt = build_index(&mut Cursor::new(&compressed_data), span)?;
+    assert_eq!(rebuilt.length, data.len() as u64);
+
+    // The index survives a round trip and seeks straight to each point
+    let mut serialized = vec![];
+    index.serialize(&mut serialized)?;
+    let index = DeflateIndex::deserialize(&mut Cursor::new(serialized))?;
+
+    let mut reader = SeekableZLibReader::new(Cursor::new(compressed_data), index);
+    for off in [0, 99_999, 100_000, 345_678, data.len() - 50] {
+        reader.seek(SeekFrom::Start(off as u64))?;
+        let mut buffer = vec![0; 50];
+        reader.read_exact(&mut buffer)?;
+        assert_eq!(buffer, &data[off..off + 50]);
+    }
+
+    Ok(())
+}
+
+#[test]
+pub fn test_seekable_gz_writer_full_flush() -> io::Result<()> {
+    test_seekable_gz_writer(SplitMode::FullFlush)
+}
+
+#[test]
+pub fn test_seekable_gz_writer_members() -> io::Result<()> {
+    test_seekable_gz_writer(SplitMode::Members)
+}
+
+fn test_parallel_writer(mode: CompressionMode, independent: bool) -> io::Result<()> {
+    let mut data = create_lines(20000);
+    data.extend(create_data(7)?);
+    let options = ParallelOptions {
+        mode,
+        block_size: 20000,
+        threads: 4,
+        independent,
+        ..ParallelOptions::default()
+    };
+
+    let mut writer = ParallelGzWriter::new(vec![], options)?;
+    for chunk in data.chunks(33333) {
+        writer.write_all(chunk)?;
+    }
+    let (compressed_data, index) = writer.finish()?;
+
+    assert_eq!(index.length, data.len() as u64);
+    assert_eq!(index.list.len(), data.len().div_ceil(20000));
+
+    // The stream is valid on its own and matches the index it came with
+    let rebuilt = build_index(&mut Cursor::new(&compressed_data), CHUNK as u64)?;
+    assert_eq!(rebuilt.length, data.len() as u64);
+
+    for (i, point) in index.list.iter().enumerate() {
+        assert_eq!(point.out, i as u64 * 20000);
+        assert_eq!(point.window.is_empty(), independent || i == 0);
+
+        let mut buffer = vec![0; 1000];
+        let n = extract_data(&compressed_data, &index, point.out, &mut buffer)?;
+        let out = point.out as usize;
+        assert_eq!(&buffer[..n], &data[out..out + n]);
+    }
+
+    let mut reader = SeekableZLibReader::new(Cursor::new(compressed_data), index);
+    let mut output = vec![];
+    reader.read_to_end(&mut output)?;
+    assert!(output == data);
+
+    Ok(())
+}
+
+#[test]
+pub fn test_parallel_writer_gzip() -> io::Result<()> {
+    test_parallel_writer(Gzip, false)?;
+    test_parallel_writer(Gzip, true)
+}
+
+#[test]
+pub fn test_parallel_writer_zlib() -> io::Result<()> {
+    test_parallel_writer(Zlib, false)?;
+    test_parallel_writer(Raw, true)
+}
+
+#[test]
+pub fn test_embedded_index() -> io::Result<()> {
+    let data = create_data(99)?;
+    let mut writer = SeekableGzWriter::new(vec![], 20000)?;
+    writer.write_all(&data)?;
+    let (compressed_data, index) = writer.finish_embedded()?;
+    assert_eq!(index.list.len(), 8);
+
+    let mut reader = SeekableZLibReader::open(compressed_data.clone())?;
+    assert_eq!(reader.index().list.len(), index.list.len());
+    reader.seek(SeekFrom::Start(123456))?;
+    let mut buffer = vec![0; 1000];
+    reader.read_exact(&mut buffer)?;
+    assert_eq!(buffer, &data[123456..124456]);
+
+    // Re-indexing stops in front of the embedded index
+    let rebuilt = build_index(&mut Cursor::new(&compressed_data), CHUNK as u64)?;
+    assert_eq!(rebuilt.length, data.len() as u64);
+
+    // Large indexes span several members, which decompress to nothing
+    let index = build_index(&mut Cursor::new(&compressed_data), CHUNK as u64)?;
+    let mut members = vec![];
+    append_index(&mut members, &index, 0)?;
+    assert!(members.len() > 65536 * 2);
+    let (embedded, offset) = read_appended_index(&members)?.unwrap();
+    assert_eq!(offset, 0);
+    assert_eq!(embedded.list.len(), index.list.len());
+    assert_eq!(
+        build_index(&mut Cursor::new(&members), CHUNK as u64)?.length,
+        0
+    );
+
+    assert!(SeekableZLibReader::open(compress(&data, Gzip as i32)?).is_err());
+
+    // A locator with a size that does not fit the members is rejected
+    // before anything is allocated for it
+    let size_at = members.len() - 10 - 8;
+    for size in [u64::MAX, 1 << 40, (members.len() + 1) as u64] {
+        let mut corrupt = members.clone();
+        corrupt[size_at..size_at + 8].copy_from_slice(&size.to_le_bytes());
+        let error = read_appended_index(&corrupt).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    Ok(())
+}
+
+fn test_tolerant_build(split: SplitMode) -> io::Result<()> {
+    let data = create_lines(30000);
+    let span = 100_000;
+    let mut writer = SeekableGzWriter::with_options(vec![], span, 6, split)?;
+    writer.write_all(&data)?;
+    let (compressed_data, index) = writer.finish()?;
+    let mut damaged_data = compressed_data.clone();
+
+    // Damage the stream at the second access point. Deflate has no check
+    // of its own, so start with a block header of the reserved type to make
+    // sure the damage is noticed right there.
+    let damage = index.list[1].inn as usize;
+    for byte in &mut damaged_data[damage..damage + 200] {
+        *byte = 0xff;
+    }
+    assert!(build_index(&mut Cursor::new(&damaged_data), span).is_err());
+
+    let recovered = IndexBuilder::new()
+        .span(span)
+        .build_tolerant(&mut Cursor::new(&damaged_data))?;
+    assert_eq!(recovered.damaged.len(), 1);
+    let gap = &recovered.damaged[0];
+    assert!(gap.start >= damage as u64 && gap.start < damage as u64 + 4);
+    assert!(gap.end <= index.list[2].inn);
+
+    // Everything before the damage and after the resync decodes as usual
+    let recovered_index = recovered.index;
+    let mut reader = SeekableZLibReader::new(Cursor::new(&damaged_data), recovered_index.clone());
+    let mut buffer = vec![0; 50_000];
+    reader.read_exact(&mut buffer)?;
+    assert_eq!(buffer, &data[..50_000]);
+
+    let resumed = recovered_index
+        .list
+        .iter()
+        .find(|point| point.inn >= gap.end)
+        .unwrap();
+    assert!(resumed.window.is_empty());
+    assert!(resumed.resync);
+    reader.seek(SeekFrom::Start(resumed.out))?;
+    let mut tail = vec![];
+    reader.read_to_end(&mut tail)?;
+    assert!(tail.len() as u64 >= data.len() as u64 - 2 * span);
+    assert!(data.ends_with(&tail));
+
+    // Thinning keeps the point past the damage, also after a round trip
+    let mut serialized = vec![];
+    recovered_index.serialize(&mut serialized)?;
+    let thinned = DeflateIndex::deserialize(&mut Cursor::new(serialized))?.thin(u64::MAX);
+    assert_eq!(thinned.list.len(), 2);
+    assert_eq!(thinned.list[1].out, resumed.out);
+    let mut reader = SeekableZLibReader::new(Cursor::new(&damaged_data), thinned);
+    reader.seek(SeekFrom::Start(resumed.out))?;
+    let mut thinned_tail = vec![];
+    reader.read_to_end(&mut thinned_tail)?;
+    assert_eq!(thinned_tail, tail);
+
+    // A truncated stream keeps its index up to the cut
+    let truncated = &compressed_data[..index.list[1].inn as usize + 100];
+    let recovered = IndexBuilder::new()
+        .span(span)
+        .build_tolerant(&mut Cursor::new(truncated))?;
+    assert_eq!(recovered.damaged.len(), 1);
+    assert_eq!(recovered.damaged[0].end, truncated.len() as u64);
+    assert!(recovered.index.length > span);
+
+    Ok(())
+}
+
+#[test]
+pub fn test_tolerant_build_full_flush() -> io::Result<()> {
+    test_tolerant_build(SplitMode::FullFlush)
+}
+
+#[test]
+pub fn test_tolerant_build_members() -> io::Result<()> {
+    test_tolerant_build(SplitMode::Members)
+}
+
+#[test]
+pub fn test_scan_blocks() -> io::Result<()> {
+    let data = create_data(7)?;
+    for window_bits in [-15, 15, 31] {
+        let compressed_data = compress(&data, window_bits)?;
+        let blocks = scan_blocks(&mut Cursor::new(&compressed_data), Auto)?;
+        assert!(blocks.len() > 1);
+
+        // Blocks are contiguous and cover all of the data
+        for pair in blocks.windows(2) {
+            assert_eq!(pair[0].offset + pair[0].compressed_bits, pair[1].offset);
+            assert_eq!(pair[0].out + pair[0].uncompressed_size, pair[1].out);
+            assert!(!pair[0].last);
+        }
+        let last = blocks.last().unwrap();
+        assert!(last.last);
+        assert_eq!(last.out + last.uncompressed_size, data.len() as u64);
+
+        // Every access point sits on a block boundary
+        let index = build_index(&mut Cursor::new(&compressed_data), CHUNK as u64)?;
+        for point in &index.list {
+            assert!(
+                blocks
+                    .iter()
+                    .any(|block| block.position() == (point.inn, point.bits)
+                        && block.out == point.out)
+            );
+        }
+    }
+
+    // Full flushes show up as empty stored blocks in front of each point
+    let mut writer = SeekableGzWriter::new(vec![], 100_000)?;
+    writer.write_all(&data)?;
+    let (compressed_data, index) = writer.finish()?;
+    let blocks = scan_blocks(&mut Cursor::new(&compressed_data), Auto)?;
+    for point in &index.list[1..] {
+        let flush = blocks
+            .iter()
+            .find(|block| block.offset + block.compressed_bits == point.inn * 8)
+            .unwrap();
+        assert_eq!(flush.kind, BlockType::Stored);
+        assert_eq!(flush.uncompressed_size, 0);
+    }
+
+    // Gzip members are followed
+    let mut writer = SeekableGzWriter::with_options(vec![], 100_000, 6, SplitMode::Members)?;
+    writer.write_all(&data)?;
+    let (compressed_data, index) = writer.finish()?;
+    let blocks = scan_blocks(&mut Cursor::new(&compressed_data), Auto)?;
+    assert_eq!(blocks.last().unwrap().member, index.list.len() - 1);
+    assert_eq!(
+        blocks.iter().filter(|block| block.last).count(),
+        index.list.len()
+    );
+
+    // Raw data that happens to start like a zlib header: a stored block with
+    // a padding bit set and a length of 29, then an empty final block
+    let mut raw = vec![0x08, 29, 0, !29, 0xff];
+    raw.extend_from_slice(&data[..29]);
+    raw.extend_from_slice(&[0x01, 0, 0, 0xff, 0xff]);
+    let forced = IndexBuilder::new()
+        .mode(Raw)
+        .build(&mut Cursor::new(&raw))?;
+    assert_eq!(forced.length, 29);
+    let blocks = scan_blocks(&mut Cursor::new(&raw), forced.mode)?;
+    assert_eq!(blocks.len(), 2);
+    assert_eq!(blocks[0].kind, BlockType::Stored);
+    assert_eq!(blocks[0].uncompressed_size, 29);
+    assert!(blocks[1].last);
+    assert!(scan_blocks(&mut Cursor::new(&raw), Auto).map_or(true, |found| found != blocks));
+
+    Ok(())
+}
+
+#[test]
+pub fn test_search() -> io::Result<()> {
+    let data = create_lines(20000);
+    let compressed_data = compress(&data, Gzip as i32)?;
+
+    for pattern in [&b"9 xxxxxxxxxx"[..], b"x\nline 12", b"line 19999"] {
+        let expected: Vec<u64> = (0..data.len())
+            .filter(|&i| data[i..].starts_with(pattern))
+            .map(|i| i as u64)
+            .collect();
+        assert!(!expected.is_empty());
+
+        // Coarse points make slices of many decoded chunks
+        for span in [CHUNK as u64, 1 << 20] {
+            // Plain index: offsets only
+            let index = build_index(&mut Cursor::new(&compressed_data), span)?;
+            let matches = search(&compressed_data, &index, pattern, 4)?;
+            let offsets: Vec<u64> = matches.iter().map(|m| m.offset).collect();
+            assert_eq!(offsets, expected);
+            assert!(matches
+                .iter()
+                .all(|m| m.line.is_none() && m.text.is_empty()));
+
+            // Line index: line numbers and text as well
+            let index = IndexBuilder::new()
+                .span(span)
+                .count_lines()
+                .build(&mut Cursor::new(&compressed_data))?;
+            let matches = search(&compressed_data, &index, pattern, 3)?;
+            assert_eq!(matches.len(), expected.len());
+            for (m, &offset) in matches.iter().zip(&expected) {
+                assert_eq!(m.offset, offset);
+                let line = data[..offset as usize]
+                    .iter()
+                    .filter(|&&b| b == b'\n')
+                    .count();
+                assert_eq!(m.line, Some(line as u64));
+                let text = data.split(|&b| b == b'\n').nth(line).unwrap();
+                assert_eq!(m.text, text);
+            }
+        }
+    }
+
+    assert!(search(&compressed_data, &DeflateIndex::new(), b"", 1).is_err());
+
+    Ok(())
+}
+
+#[test]
+pub fn test_find_first() -> io::Result<()> {
+    // Sorted records with keys 0, 3, 6, ...
+    let mut data = vec![];
+    for i in 0..100_000u64 {
+        data.extend_from_slice(
+            format!("{:08} {}\n", i * 3, "x".repeat(i as usize % 29)).as_bytes(),
+        );
+    }
+    let compressed_data = compress(&data, Gzip as i32)?;
+    let key =
+        |record: &[u8]| -> u64 { std::str::from_utf8(&record[..8]).unwrap().parse().unwrap() };
+    let offset_of = |key: u64| -> Option<u64> {
+        let needle = format!("{:08} ", key.div_ceil(3) * 3);
+        (0..data.len())
+            .find(|&i| (i == 0 || data[i - 1] == b'\n') && data[i..].starts_with(needle.as_bytes()))
+            .map(|i| i as u64)
+    };
+
+    let plain = build_index(&mut Cursor::new(&compressed_data), CHUNK as u64)?;
+    let lines = IndexBuilder::new()
+        .span(CHUNK as u64)
+        .count_lines()
+        .build(&mut Cursor::new(&compressed_data))?;
+    for index in [plain, lines] {
+        assert!(index.list.len() > 4);
+        for target in [0, 1, 3, 4, 12345, 150_001, 299_997] {
+            let found = find_first(&compressed_data, &index, |record| key(record).cmp(&target))?;
+            assert_eq!(found, offset_of(target), "target {}", target);
+        }
+        assert_eq!(
+            find_first(&compressed_data, &index, |record| key(record).cmp(&299_998))?,
+            None
+        );
+
+        // Only a handful of spans are decoded
+        let source = CountingSource::new(compressed_data.clone());
+        let reader = SeekableZLibReader::from_source(&source, index);
+        let found = reader.find_first(|record| key(record).cmp(&200_000))?;
+        assert_eq!(found, offset_of(200_000));
+        assert!(source.fetched.get() < compressed_data.len() as u64 / 2);
+    }
+
+    Ok(())
+}
+
+// Appends a ustar header and the data of one entry
+fn append_tar_entry(tar: &mut Vec<u8>, name: &str, kind: u8, data: &[u8]) {
+    let mut header = vec![0u8; 512];
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    header[100..107].copy_from_slice(b"0000644");
+    header[124..135].copy_from_slice(format!("{:011o}", data.len()).as_bytes());
+    header[136..147].copy_from_slice(b"00000000000");
+    header[148..156].copy_from_slice(b"        ");
+    header[156] = kind;
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+    let checksum: u32 = header.iter().map(|&b| b as u32).sum();
+    header[148..155].copy_from_slice(format!("{:06o}\0", checksum).as_bytes());
+
+    tar.extend_from_slice(&header);
+    tar.extend_from_slice(data);
+    tar.resize(tar.len().div_ceil(512) * 512, 0);
+}
+
+#[test]
+pub fn test_tar_index() -> io::Result<()> {
+    let files: Vec<(String, Vec<u8>)> = (0..20)
+        .map(|i| (format!("dir/file{}.txt", i), create_lines(500 * i)))
+        .collect();
+    let long_name = format!("{}/deep.bin", "d".repeat(150));
+    let long_data = create_data(3)?;
+
+    let mut tar = vec![];
+    append_tar_entry(&mut tar, "dir/", b'5', b"");
+    for (name, data) in &files {
+        append_tar_entry(&mut tar, name, b'0', data);
+    }
+    // The record length counts its own three digits
+    let pax = format!("{} path={}\n", long_name.len() + 10, long_name);
+    append_tar_entry(&mut tar, "PaxHeader", b'x', pax.as_bytes());
+    append_tar_entry(&mut tar, "placeholder", b'0', &long_data);
+    tar.resize(tar.len() + 1024, 0);
+
+    let compressed_data = compress(&tar, Gzip as i32)?;
+    let builder = IndexBuilder::new().span(CHUNK as u64);
+    let (index, entries) = build_tar_index(&mut Cursor::new(&compressed_data), &builder)?;
+    assert_eq!(index.length, tar.len() as u64);
+    assert_eq!(entries.len(), files.len() + 2);
+    assert_eq!(entries[0].kind, b'5');
+    assert_eq!(entries[1].mode, 0o644);
+    assert_eq!(entries.last().unwrap().name, long_name);
+
+    let mut archive = TarArchive::open(compressed_data, &builder)?;
+    for (name, data) in files.iter().rev() {
+        let mut entry = archive.open_entry(name)?;
+        let mut contents = vec![];
+        entry.read_to_end(&mut contents)?;
+        assert_eq!(&contents, data);
+    }
+
+    let mut entry = archive.open_entry(&long_name)?;
+    entry.seek(SeekFrom::End(-100))?;
+    let mut tail = vec![];
+    entry.read_to_end(&mut tail)?;
+    assert_eq!(tail, &long_data[long_data.len() - 100..]);
+    entry.seek(SeekFrom::Start(1000))?;
+    let mut buffer = vec![0; 10];
+    entry.read_exact(&mut buffer)?;
+    assert_eq!(buffer, &long_data[1000..1010]);
+    assert!(entry.seek(SeekFrom::Current(-2000)).is_err());
+
+    assert!(archive.open_entry("missing").is_err());
+
+    // Records too short to hold their own length are ignored
+    for pax in ["2 ", "1 x", "3 \n"] {
+        let mut tar = vec![];
+        append_tar_entry(&mut tar, "PaxHeader", b'x', pax.as_bytes());
+        append_tar_entry(&mut tar, "file", b'0', b"data");
+        tar.resize(tar.len() + 1024, 0);
+        let compressed_data = compress(&tar, Gzip as i32)?;
+        let (_, entries) = build_tar_index(&mut Cursor::new(&compressed_data), &builder)?;
+        assert_eq!(entries.last().unwrap().name, "file");
+    }
+
+    // Vendor metadata is not listed, and a pax size skips a long name
+    // header on its way to the entry
+    let data = create_lines(100);
+    let mut tar = vec![];
+    append_tar_entry(
+        &mut tar,
+        "PaxHeader",
+        b'x',
+        format!("13 size={}\n", data.len()).as_bytes(),
+    );
+    append_tar_entry(&mut tar, "././@LongLink", b'K', b"target/of/the/link");
+    append_tar_entry(&mut tar, "././@LongLink", b'L', b"long/name");
+    append_tar_entry(&mut tar, "file", b'0', &data);
+    append_tar_entry(&mut tar, "ExtendedHeader", b'X', b"10 a=bcde\n");
+    append_tar_entry(&mut tar, "link", b'2', b"");
+    tar.resize(tar.len() + 1024, 0);
+    let compressed_data = compress(&tar, Gzip as i32)?;
+    let (_, entries) = build_tar_index(&mut Cursor::new(&compressed_data), &builder)?;
+    let names: Vec<&str> = entries.iter().map(|entry| entry.name.as_str()).collect();
+    assert_eq!(names, ["long/name", "link"]);
+    assert_eq!(entries[0].size, data.len() as u64);
+
+    // Metadata is not buffered without bound
+    let mut tar = vec![];
+    append_tar_entry(&mut tar, "././@LongLink", b'L', &vec![b'x'; 2 << 20]);
+    append_tar_entry(&mut tar, "file", b'0', b"data");
+    tar.resize(tar.len() + 1024, 0);
+    let compressed_data = compress(&tar, Gzip as i32)?;
+    let err = build_tar_index(&mut Cursor::new(&compressed_data), &builder).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+    Ok(())
+}
+
+// Writes a zip archive of deflated entries, with a comment after the end
+// of central directory record
+fn create_zip(files: &[(&str, &[u8])]) -> io::Result<Vec<u8>> {
+    let mut zip = vec![];
+    let mut directory = vec![];
+    for &(name, data) in files {
+        let compressed = compress(data, Raw as i32)?;
+        let offset = zip.len() as u32;
+        let mut sizes = vec![];
+        sizes.extend_from_slice(&0u32.to_le_bytes()); // crc, not checked
+        sizes.extend_from_slice(&(compressed.len() as u32).to_le_bytes());
+        sizes.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        sizes.extend_from_slice(&(name.len() as u16).to_le_bytes());
+
+        zip.extend_from_slice(&[0x50, 0x4b, 3, 4, 20, 0, 0, 0, 8, 0, 0, 0, 0, 0]);
+        zip.extend_from_slice(&sizes);
+        zip.extend_from_slice(&3u16.to_le_bytes());
+        zip.extend_from_slice(name.as_bytes());
+        zip.extend_from_slice(b"xyz");
+        zip.extend_from_slice(&compressed);
+
+        directory.extend_from_slice(&[0x50, 0x4b, 1, 2, 20, 0, 20, 0, 0, 0, 8, 0, 0, 0, 0, 0]);
+        directory.extend_from_slice(&sizes);
+        directory.extend_from_slice(&[0; 12]);
+        directory.extend_from_slice(&offset.to_le_bytes());
+        directory.extend_from_slice(name.as_bytes());
+    }
+
+    let offset = zip.len() as u32;
+    zip.extend_from_slice(&directory);
+    zip.extend_from_slice(&[0x50, 0x4b, 5, 6, 0, 0, 0, 0]);
+    zip.extend_from_slice(&(files.len() as u16).to_le_bytes());
+    zip.extend_from_slice(&(files.len() as u16).to_le_bytes());
+    zip.extend_from_slice(&(directory.len() as u32).to_le_bytes());
+    zip.extend_from_slice(&offset.to_le_bytes());
+    zip.extend_from_slice(&7u16.to_le_bytes());
+    zip.extend_from_slice(b"comment");
+    Ok(zip)
+}
+
+#[test]
+pub fn test_zip_entries() -> io::Result<()> {
+    let small = create_lines(10);
+    let large = create_data(77)?;
+    let zip = create_zip(&[("small.txt", &small), ("large.bin", &large)])?;
+
+    // A directory size or offset reaching past the end record is refused
+    // before anything is allocated for it
+    let size_at = zip.len() - 7 - 2 - 4 - 4;
+    for (at, value) in [(size_at, 0xffff_fff0u32), (size_at + 4, 0xffff_fff0)] {
+        let mut corrupt = zip.clone();
+        corrupt[at..at + 4].copy_from_slice(&value.to_le_bytes());
+        let error = ZipArchive::new(corrupt).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    let archive = ZipArchive::new(zip)?;
+    assert_eq!(archive.entries().len(), 2);
+    assert_eq!(archive.entry("large.bin").unwrap().size, large.len() as u64);
+
+    let index = archive.build_index("large.bin", &IndexBuilder::new().span(CHUNK as u64))?;
+    assert_eq!(index.mode, Raw);
+    assert!(index.list.len() > 2);
+
+    // The index survives a round trip, and reads stay within the entry
+    let mut serialized = vec![];
+    index.serialize(&mut serialized)?;
+    let index = DeflateIndex::deserialize(&mut Cursor::new(serialized))?;
+    let mut reader = archive.open_entry("large.bin", index.clone())?;
+    reader.seek(SeekFrom::Start(100_000))?;
+    let mut buffer = vec![0; 1000];
+    reader.read_exact(&mut buffer)?;
+    assert_eq!(buffer, &large[100_000..101_000]);
+    reader.seek(SeekFrom::End(-10))?;
+    let mut tail = vec![];
+    reader.read_to_end(&mut tail)?;
+    assert_eq!(tail, &large[large.len() - 10..]);
+
+    let index = archive.build_index("small.txt", &IndexBuilder::new())?;
+    let mut contents = vec![];
+    archive
+        .open_entry("small.txt", index.clone())?
+        .read_to_end(&mut contents)?;
+    assert_eq!(contents, small);
+
+    // An index of another entry is refused
+    assert!(archive.open_entry("large.bin", index).is_err());
+    assert!(archive
+        .build_index("missing", &IndexBuilder::new())
+        .is_err());
+    assert!(ZipArchive::new(large).is_err());
+
+    Ok(())
+}
+
+#[derive(Debug, Clone)]
+enum SeekOp {
+    Seek(SeekFrom),
+    SeekRelative(i64),
+    Rewind,
+    Read(usize),
+}
+
+fn seek_op(length: i64) -> impl Strategy<Value = SeekOp> {
+    prop_oneof![
+        (0..length as u64 * 2).prop_map(|offset| SeekOp::Seek(SeekFrom::Start(offset))),
+        (-length * 2..length).prop_map(|offset| SeekOp::Seek(SeekFrom::End(offset))),
+        (-length..length).prop_map(|offset| SeekOp::Seek(SeekFrom::Current(offset))),
+        (-100i64..100).prop_map(SeekOp::SeekRelative),
+        Just(SeekOp::Seek(SeekFrom::Current(i64::MIN))),
+        Just(SeekOp::Rewind),
+        (0usize..5000).prop_map(SeekOp::Read),
+    ]
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(64))]
+
+    // Seeking and reading behaves exactly like a Cursor over the data
+    #[test]
+    fn test_seek_matches_cursor(ops in prop::collection::vec(seek_op(160000), 1..40)) {
+        let data = create_data(4242).unwrap();
+        let compressed_data = compress(&data, Gzip as i32).unwrap();
+        let index = build_index(&mut Cursor::new(&compressed_data), 8192).unwrap();
+        let mut reader = SeekableZLibReader::new(Cursor::new(compressed_data), index);
+        let mut cursor = Cursor::new(&data);
+
+        for op in ops {
+            match op {
+                SeekOp::Seek(pos) => {
+                    let expected = cursor.seek(pos).map_err(|e| e.kind());
+                    prop_assert_eq!(reader.seek(pos).map_err(|e| e.kind()), expected);
+                }
+                SeekOp::SeekRelative(offset) => {
+                    let expected = cursor.seek(SeekFrom::Current(offset)).map(|_| ()).map_err(|e| e.kind());
+                    prop_assert_eq!(reader.seek_relative(offset).map_err(|e| e.kind()), expected);
+                }
+                SeekOp::Rewind => {
+                    cursor.rewind().unwrap();
+                    reader.rewind().unwrap();
+                }
+                SeekOp::Read(len) => {
+                    let mut expected = vec![0; len];
+                    let n = cursor.read(&mut expected).unwrap();
+                    let mut buffer = vec![0; n];
+                    reader.read_exact(&mut buffer).unwrap();
+                    prop_assert_eq!(buffer, &expected[..n]);
+                    if n < len {
+                        prop_assert_eq!(reader.read(&mut [0; 1]).unwrap(), 0);
+                    }
+                }
+            }
+            prop_assert_eq!(reader.stream_position().unwrap(), cursor.position());
+        }
+    }
+}
+
+#[test]
+pub fn test_seek_within_buffer() -> io::Result<()> {
+    let data = create_data(4243)?;
+    let compressed_data = compress(&data, Gzip as i32)?;
+    let index = build_index(&mut Cursor::new(&compressed_data), 8192)?;
+    let source = CountingSource::new(compressed_data);
+    let mut reader = SeekableZLibReader::from_source(&source, index);
+
+    reader.seek(SeekFrom::Start(50_000))?;
+    let mut buffer = vec![0; 100];
+    reader.read_exact(&mut buffer)?;
+    let fetched = source.fetched.get();
+
+    // Moving around inside the buffered data decodes nothing new
+    reader.seek_relative(-60)?;
+    reader.read_exact(&mut buffer)?;
+    assert_eq!(buffer, &data[50_040..50_140]);
+    reader.seek(SeekFrom::Start(50_000))?;
+    assert_eq!(reader.stream_position()?, 50_000);
+    reader.read_exact(&mut buffer)?;
+    assert_eq!(buffer, &data[50_000..50_100]);
+    assert_eq!(source.fetched.get(), fetched);
+
+    Ok(())
+}
+
+#[test]
+pub fn test_seek_into_history() -> io::Result<()> {
+    let data = create_data(4244)?;
+    let compressed_data = compress(&data, Gzip as i32)?;
+    let index = build_index(&mut Cursor::new(&compressed_data), 8192)?;
+
+    for (history, decodes) in [(0, true), (CHUNK, false), (100_000, false)] {
+        let source = CountingSource::new(compressed_data.clone());
+        let mut reader =
+            SeekableZLibReader::from_source(&source, index.clone()).with_history(history);
+        let mut buffer = vec![0; 40_000];
+        reader.read_exact(&mut buffer)?;
+        let fetched = source.fetched.get();
+
+        // Peek back across the last refill
+        reader.seek(SeekFrom::Current(-10_000))?;
+        let mut peek = vec![0; 10_000];
+        reader.read_exact(&mut peek)?;
+        assert_eq!(peek, &data[30_000..40_000]);
+        assert_eq!(source.fetched.get() != fetched, decodes);
+    }
+
+    Ok(())
+}
+
+#[test]
+pub fn test_sequential_reads_decode_once() -> io::Result<()> {
+    let data = create_hex_lines(4245);
+    let compressed_data = compress(&data, Gzip as i32)?;
+    let index = build_index(&mut Cursor::new(&compressed_data), 1 << 18)?;
+    assert!(index.list.len() > 1);
+
+    // Reading line by line fetches the compressed data about once
+    let source = CountingSource::new(compressed_data.clone());
+    let reader = SeekableZLibReader::from_source(&source, index.clone());
+    let mut read = vec![];
+    for line in reader.lines() {
+        read.extend_from_slice(line?.as_bytes());
+        read.push(b'\n');
+    }
+    assert_eq!(read, data);
+    let slack = (index.list.len() * CHUNK) as u64;
+    assert!(source.fetched.get() <= compressed_data.len() as u64 + slack);
+
+    // So does skipping ahead within a span and reading on
+    let source = CountingSource::new(compressed_data.clone());
+    let mut reader = SeekableZLibReader::from_source(&source, index);
+    let mut buffer = vec![0; 1000];
+    for offset in (0..data.len() - 1000).step_by(50_000) {
+        reader.seek(SeekFrom::Start(offset as u64))?;
+        reader.read_exact(&mut buffer)?;
+        assert_eq!(buffer, &data[offset..offset + 1000]);
+    }
+    assert!(source.fetched.get() <= compressed_data.len() as u64 + slack);
+
+    Ok(())
+}
+
+#[test]
+pub fn test_buffer_sizes() -> io::Result<()> {
+    let data = create_data(4343)?;
+    let compressed_data = compress(&data, Gzip as i32)?;
+    let expected = build_index(&mut Cursor::new(&compressed_data), 8192)?;
+
+    // The input size does not change the index
+    for input in [1, 100, 1 << 20] {
+        let index = IndexBuilder::new()
+            .span(8192)
+            .input_buffer_size(input)
+            .build(&mut Cursor::new(&compressed_data))?;
+        assert_eq!(index.list.len(), expected.list.len());
+        assert_eq!(index.length, expected.length);
+    }
+
+    for (input, output, read_ahead) in [(1, 6, 1000), (100, 10, 1 << 20), (1 << 20, 1 << 16, 7)] {
+        let mut reader = SeekableZLibReader::from_source(&compressed_data, expected.clone())
+            .with_input_buffer_size(input)
+            .with_output_buffer_size(output)
+            .with_read_ahead(read_ahead);
+
+        // A random read decodes no more than the output size
+        reader.seek(SeekFrom::Start(50_000))?;
+        let mut buffer = [0; 5];
+        reader.read_exact(&mut buffer)?;
+        assert_eq!(buffer, data[50_000..50_005]);
+        assert!(reader.fill_buf()?.len() < output);
+
+        let mut rest = vec![];
+        reader.read_to_end(&mut rest)?;
+        assert_eq!(rest, &data[50_005..]);
+    }
+
+    Ok(())
+}
+
+#[test]
+pub fn test_preset_dictionary() -> io::Result<()> {
+    let dictionary = create_lines(2000);
+    let mut data = vec![];
+    for i in 0..20000u64 {
+        let line = (i * 37 % 2000) as usize;
+        let start = (dictionary.len() - 40) * line / 2000;
+        data.extend_from_slice(&dictionary[start..start + 40]);
+        data.extend_from_slice(format!("entry {} {}\n", i, i * i * 7919 % 1000003).as_bytes());
+    }
+
+    for mode in [Zlib, Raw] {
+        let compressed_data = compress_with_dictionary(&data, &dictionary, mode as i32);
+        let builder = IndexBuilder::new().span(1024);
+        if mode == Zlib {
+            assert_eq!(compressed_data[1] & 0x20, 0x20); // FDICT
+            assert!(builder.build(&mut Cursor::new(&compressed_data)).is_err());
+            let wrong = builder.clone().with_dictionary(&dictionary[1..]);
+            let err = wrong.build(&mut Cursor::new(&compressed_data)).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        }
+
+        let builder = builder.mode(mode).with_dictionary(&dictionary);
+        let index = builder.build(&mut Cursor::new(&compressed_data))?;
+        assert_eq!(index.length, data.len() as u64);
+        assert!(index.list.len() > 2);
+
+        let mut buffer = vec![0; 100];
+        for offset in (0..data.len() - 100).step_by(9973) {
+            let n = extract_data(&compressed_data, &index, offset as u64, &mut buffer)?;
+            assert_eq!(buffer[..n], data[offset..offset + 100]);
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+pub fn test_small_windows() -> io::Result<()> {
+    let data = create_data(4545)?;
+
+    for window_bits in [9, 12, 15] {
+        let compressed_data = compress(&data, window_bits)?;
+        let index = build_index(&mut Cursor::new(&compressed_data), 8192)?;
+        assert_eq!(index.window_size, 1 << window_bits);
+        assert!(index.list.len() > 2);
+        for point in &index.list {
+            assert_eq!(point.window.len(), 1 << window_bits);
+        }
+
+        let mut serialized = vec![];
+        index.serialize(&mut serialized)?;
+        let index = DeflateIndex::deserialize(&mut Cursor::new(serialized))?;
+        assert_eq!(index.window_size, 1 << window_bits);
+
+        let mut buffer = vec![0; 1000];
+        for offset in (0..data.len() - 1000).step_by(4099) {
+            let n = extract_data(&compressed_data, &index, offset as u64, &mut buffer)?;
+            assert_eq!(buffer[..n], data[offset..offset + 1000]);
+        }
+    }
+
+    // Gzip does not declare its window size
+    let compressed_data = compress(&data, Gzip as i32)?;
+    let index = build_index(&mut Cursor::new(&compressed_data), 8192)?;
+    assert_eq!(index.window_size, 32768);
+
+    Ok(())
+}
+
+#[test]
+pub fn test_detect_mode() -> io::Result<()> {
+    let detect = |header: &[u8]| unsafe {
+        let mut stream = new_z_stream();
+        stream.next_in = header.as_ptr() as *mut u8;
+        stream.avail_in = header.len() as u32;
+        detect_mode(&stream)
+    };
+    assert_eq!(detect(&[0x78, 0x9c]), Zlib);
+    assert_eq!(detect(&[0x08, 0x1d]), Zlib); // 256 byte window
+    assert_eq!(detect(&[0x78, 0x9d]), Raw); // FCHECK does not match
+    assert_eq!(detect(&[0x88, 0x98]), Raw); // window too large
+    assert_eq!(detect(&[0x1f, 0x8b, 8, 0x1c]), Gzip);
+    assert_eq!(detect(&[0x1f, 0x8b, 8, 0x20]), Raw); // reserved flag
+    assert_eq!(detect(&[0x1f, 0x8b, 7, 0]), Raw); // not deflate
+    assert_eq!(detect(&[0x1f]), Raw);
+    assert_eq!(detect(&[]), Raw);
+
+    // A mode that is set is used as is
+    let data = create_data(4646)?;
+    for mode in [Raw, Zlib, Gzip] {
+        let compressed_data = compress(&data, mode as i32)?;
+        let index = IndexBuilder::new()
+            .mode(mode)
+            .build(&mut Cursor::new(&compressed_data))?;
+        assert_eq!(index.mode, mode);
+        assert_eq!(index.length, data.len() as u64);
+
+        let wrong = if mode == Raw { Gzip } else { Raw };
+        let result = IndexBuilder::new()
+            .mode(wrong)
+            .build(&mut Cursor::new(&compressed_data));
+        assert!(result.map_or(true, |index| index.length != data.len() as u64));
+
+        let mut serialized = vec![];
+        index.serialize(&mut serialized)?;
+        assert_eq!(
+            DeflateIndex::deserialize(&mut Cursor::new(&serialized))?.mode,
+            mode
+        );
+        serialized[14..18].copy_from_slice(&7i32.to_be_bytes());
+        assert!(DeflateIndex::deserialize(&mut Cursor::new(&serialized)).is_err());
+    }
+
+    let options = ParallelOptions {
+        mode: Auto,
+        ..ParallelOptions::default()
+    };
+    assert!(ParallelGzWriter::new(vec![], options).is_err());
+
+    Ok(())
+}
+
+#[test]
+pub fn test_trailing_data() -> io::Result<()> {
+    let data = create_data(4747)?;
+    let mut members = compress(&data[..60000], Gzip as i32)?;
+    members.extend(compress(&data[60000..], Gzip as i32)?);
+    let end = members.len() as u64;
+
+    let build = |compressed: &[u8], trailing| {
+        IndexBuilder::new()
+            .span(8192)
+            .trailing_data(trailing)
+            .build(&mut Cursor::new(compressed))
+    };
+    let read_all = |compressed: &[u8], index: DeflateIndex| -> io::Result<Vec<u8>> {
+        let mut reader = SeekableZLibReader::from_source(compressed, index);
+        reader.seek(SeekFrom::End(-10))?;
+        let mut tail = [0; 10];
+        reader.read_exact(&mut tail)?;
+        reader.rewind()?;
+        let mut all = vec![];
+        reader.read_to_end(&mut all)?;
+        Ok(all)
+    };
+
+    let index = build(&members, TrailingData::Error)?;
+    assert_eq!(index.data_end, end);
+    assert!(!index.zero_padding);
+
+    // An index that does not know where the data ends reads every member
+    let mut unknown = index.clone();
+    unknown.data_end = 0;
+    assert_eq!(read_all(&members, unknown)?, data);
+
+    let mut padded = members.clone();
+    padded.resize(padded.len() + 5000, 0);
+    assert!(build(&padded, TrailingData::Error).is_err());
+    for trailing in [TrailingData::Stop, TrailingData::SkipZeros] {
+        let index = build(&padded, trailing)?;
+        assert_eq!(index.length, data.len() as u64);
+        assert_eq!(index.data_end, end);
+        assert_eq!(read_all(&padded, index)?, data);
+    }
+
+    // Padding between members
+    let mut padded = compress(&data[..60000], Gzip as i32)?;
+    padded.resize(padded.len() + 5000, 0);
+    padded.extend(compress(&data[60000..], Gzip as i32)?);
+    let index = build(&padded, TrailingData::SkipZeros)?;
+    assert_eq!(index.data_end, padded.len() as u64);
+    assert!(index.zero_padding);
+    assert_eq!(read_all(&padded, index.clone())?, data);
+    // Padding is only skipped for indexes that allow it
+    let mut strict = index;
+    strict.zero_padding = false;
+    assert!(read_all(&padded, strict).is_err());
+    let index = build(&padded, TrailingData::Stop)?;
+    assert_eq!(index.length, 60000);
+    assert_eq!(read_all(&padded, index)?, &data[..60000]);
+
+    let mut garbage = members.clone();
+    garbage.extend_from_slice(b"\0\0\0garbage");
+    assert!(build(&garbage, TrailingData::Error).is_err());
+    assert!(build(&garbage, TrailingData::SkipZeros).is_err());
+    let index = build(&garbage, TrailingData::Stop)?;
+    assert_eq!(index.data_end, end);
+    assert_eq!(read_all(&garbage, index)?, data);
+
+    // Other formats end with their stream
+    let compressed_data = compress(&data, Zlib as i32)?;
+    let index = build_index(&mut Cursor::new(&compressed_data), 8192)?;
+    assert_eq!(index.data_end, compressed_data.len() as u64);
+
+    Ok(())
+}
+
+#[test]
+pub fn test_concat_reader() -> io::Result<()> {
+    let data = create_data(4848)?;
+    let pieces = [&data[..70000], &data[70000..70000], &data[70000..]];
+    let parts: Vec<_> = pieces
+        .iter()
+        .zip([Gzip, Gzip, Zlib])
+        .map(|(piece, mode)| {
+            let compressed_data = compress(piece, mode as i32).unwrap();
+            let index = build_index(&mut Cursor::new(&compressed_data), 8192).unwrap();
+            (compressed_data, index)
+        })
+        .collect();
+
+    let mut reader = ConcatSeekableReader::new(parts);
+    assert_eq!(reader.len(), data.len() as u64);
+    assert_eq!(reader.locate(0), Some((0, 0)));
+    assert_eq!(reader.locate(69999), Some((0, 69999)));
+    assert_eq!(reader.locate(70000), Some((2, 0)));
+    assert_eq!(reader.locate(data.len() as u64), None);
+
+    let mut all = vec![];
+    reader.read_to_end(&mut all)?;
+    assert_eq!(all, data);
+
+    // Reads across the end of a file continue in the next one
+    for offset in [0, 69990, 70000, 100000, 159990] {
+        reader.seek(SeekFrom::Start(offset))?;
+        let mut buffer = vec![];
+        (&mut reader).take(20000).read_to_end(&mut buffer)?;
+        let end = std::cmp::min(offset as usize + 20000, data.len());
+        assert_eq!(buffer, &data[offset as usize..end]);
+    }
+
+    reader.seek(SeekFrom::End(-5))?;
+    let mut tail = vec![];
+    reader.read_to_end(&mut tail)?;
+    assert_eq!(tail, &data[data.len() - 5..]);
+    assert!(reader
+        .seek(SeekFrom::Current(-(data.len() as i64) - 1))
+        .is_err());
+
+    Ok(())
+}
+
+#[test]
+pub fn test_index_stats() -> io::Result<()> {
+    let data = create_data(4949)?;
+    let mut compressed_data = vec![];
+    let mut writer =
+        SeekableGzWriter::with_options(&mut compressed_data, 40000, 6, SplitMode::Members)?;
+    writer.write_all(&data)?;
+    let (_, written) = writer.finish()?;
+    let index = build_index(&mut Cursor::new(&compressed_data), 8192)?;
+
+    let stats = index.stats();
+    assert_eq!(stats.points, index.list.len());
+    assert_eq!(stats.length, data.len() as u64);
+    assert_eq!(stats.compressed_length, compressed_data.len() as u64);
+    assert_eq!(stats.members, 4);
+    assert_eq!(written.stats().members, 4);
+    assert_eq!(stats.span_ratios.len(), stats.points);
+    assert!(stats.average_span <= stats.max_span && stats.max_span <= 40000);
+    assert!(stats.average_compressed_span <= stats.max_compressed_span);
+    assert!(stats.worst_seek_cost >= stats.max_span);
+    let windows: usize = index.list.iter().map(|point| point.window.len()).sum();
+    assert_eq!(stats.window_bytes, windows as u64);
+
+    let mut serialized = vec![];
+    index.serialize(&mut serialized)?;
+    assert_eq!(
+        DeflateIndex::deserialize(&mut Cursor::new(serialized))?.members,
+        4
+    );
+
+    // No window contents in the debug output
+    let debug = format!("{:?}", index.list[1]);
+    assert!(debug.contains("window: <32768 bytes>"), "{}", debug);
+    assert!(format!("{:?}", index).len() < 200 * index.list.len());
+    assert_eq!(
+        index.to_string(),
+        format!(
+            "gzip index: {} bytes from {} compressed, {} access points, 4 members",
+            data.len(),
+            compressed_data.len(),
+            index.list.len()
+        )
+    );
+
+    Ok(())
+}
+
+#[test]
+pub fn test_thin_densify_slice() -> io::Result<()> {
+    let data = create_hex_lines(5050);
+    let compressed_data = compress(&data, Gzip as i32)?;
+    let build = |span| {
+        IndexBuilder::new()
+            .span(span)
+            .count_lines()
+            .build(&mut Cursor::new(&compressed_data))
+    };
+    let full = build(4096)?;
+    let coarse = build(65536)?;
+
+    // Every point must decode from itself. Points taken from the indexes
+    // derived from are unchanged, and added ones hold the data before them.
+    let check = |index: &DeflateIndex, from: &[&DeflateIndex]| -> io::Result<()> {
+        for point in &index.list {
+            let out = point.out as usize;
+            let mut points = from.iter().flat_map(|index| &index.list);
+            if let Some(from) = points.find(|p| p.out == point.out) {
+                assert_eq!(point.window, from.window);
+            } else {
+                let mut window = vec![0; 32768usize.saturating_sub(out)];
+                window.extend_from_slice(&data[out.saturating_sub(32768)..out]);
+                assert_eq!(point.window, window);
+            }
+            assert_eq!(point.lines, count_delimiters(&data[..out], b'\n'));
+            let record = data[out.saturating_sub(1)..]
+                .iter()
+                .position(|&b| b == b'\n')
+                .map_or(data.len(), |i| out.saturating_sub(1) + i + 1);
+            assert_eq!(point.record, if out == 0 { 0 } else { record as u64 });
+
+            let alone = index.slice(point.out, point.out + 1);
+            assert_eq!(alone.list.len(), 1);
+            let mut buffer = vec![0; 1000];
+            let n = extract_data(&compressed_data, &alone, point.out, &mut buffer)?;
+            assert_eq!(
+                buffer[..n],
+                data[out..std::cmp::min(out + 1000, data.len())]
+            );
+        }
+        Ok(())
+    };
+
+    let thinned = full.thin(65536);
+    assert!(thinned.list.len() < full.list.len());
+    assert!(thinned
+        .list
+        .windows(2)
+        .all(|w| w[1].out - w[0].out >= 65536));
+    check(&thinned, &[&full])?;
+
+    let dense = densify(&compressed_data, &coarse, 100_000, 200_000, 4096)?;
+    let added = dense.list.len() - coarse.list.len();
+    assert!(added >= 2, "{} points added", added);
+    assert!(dense
+        .list
+        .iter()
+        .all(|point| point.out < 200_000 || coarse.list.iter().any(|p| p.out == point.out)));
+    check(&dense, &[&coarse])?;
+
+    let slice = full.slice(100_000, 150_000);
+    assert!(slice.list[0].out <= 100_000 && slice.list.last().unwrap().out < 150_000);
+    let mut buffer = vec![0; 50_000];
+    let n = extract_data(&compressed_data, &slice, 100_000, &mut buffer)?;
+    assert_eq!(buffer[..n], data[100_000..150_000]);
+    assert!(extract_data(&compressed_data, &slice, 0, &mut buffer).is_err());
+
+    let merged = coarse.merge(&slice)?;
+    let mut outs: Vec<u64> = coarse
+        .list
+        .iter()
+        .chain(&slice.list)
+        .map(|p| p.out)
+        .collect();
+    outs.sort_unstable();
+    outs.dedup();
+    assert_eq!(merged.list.iter().map(|p| p.out).collect::<Vec<_>>(), outs);
+    check(&merged, &[&coarse, &slice])?;
+    let other = build_index(&mut Cursor::new(compress(&data, Zlib as i32)?), 4096)?;
+    assert!(coarse.merge(&other).is_err());
+
+    Ok(())
+}
+
+#[test]
+pub fn test_fingerprint_serialization() -> io::Result<()> {
+    let data = create_data(1000)?;
+    let compressed_data = compress(&data, Gzip as i32)?;
+    let mut index = build_index(&mut Cursor::new(&compressed_data), 8192)?;
+    index.fingerprint = Some("\"5d41-abc\"".to_string());
+
+    let mut serialized = vec![];
+    index.serialize(&mut serialized)?;
+    let index = DeflateIndex::deserialize(&mut Cursor::new(serialized))?;
+    assert_eq!(index.fingerprint.as_deref(), Some("\"5d41-abc\""));
+
+    // Sources that cannot tell their fingerprint are not checked
+    let mut buffer = vec![0; 100];
+    extract_data(&compressed_data, &index, 1000, &mut buffer)?;
+    assert_eq!(buffer, &data[1000..1100]);
+
+    let mut long = index.clone();
+    long.fingerprint = Some("x".repeat(2000));
+    assert!(long.serialize(&mut vec![]).is_err());
+
+    Ok(())
+}
+
+#[test]
+pub fn test_serialization_format() -> io::Result<()> {
+    let data = create_data(1000)?;
+    let compressed_data = compress(&data, Gzip as i32)?;
+    let index = build_index(&mut Cursor::new(&compressed_data), 8192)?;
+    assert!(index.list.len() > 2);
+
+    let mut serialized = vec![];
+    index.serialize(&mut serialized)?;
+    assert_eq!(&serialized[..4], b"ZRAN");
+    DeflateIndex::deserialize(&mut Cursor::new(&serialized))?;
+
+    // Data without the magic is not an index
+    let err = DeflateIndex::deserialize(&mut Cursor::new(&serialized[6..])).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+    // Nor is a format version this build does not know
+    let mut future = serialized.clone();
+    future[5] += 1;
+    let err = DeflateIndex::deserialize(&mut Cursor::new(future)).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    assert!(err.to_string().contains("version 2"));
+
+    // Access points must not go backwards
+    let mut swapped = index.clone();
+    swapped.list.swap(1, 2);
+    let mut serialized = vec![];
+    swapped.serialize(&mut serialized)?;
+    let err = DeflateIndex::deserialize(&mut Cursor::new(serialized)).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+    // Statistics of such an index do not overflow
+    swapped.stats();
+
+    // Nor may a point lie past the end of the data, or start inside a byte
+    // that does not exist
+    let mut past_length = index.clone();
+    past_length.length = past_length.list[2].out - 1;
+    let mut past_data_end = index.clone();
+    past_data_end.data_end = past_data_end.list[2].inn - 1;
+    let mut before_start = index.clone();
+    before_start.list.truncate(1);
+    before_start.list[0].inn = 0;
+    before_start.list[0].bits = 3;
+    for bad in [past_length, past_data_end, before_start] {
+        let mut serialized = vec![];
+        bad.serialize(&mut serialized)?;
+        let err = DeflateIndex::deserialize(&mut Cursor::new(serialized)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    Ok(())
+}
+
+#[test]
+pub fn test_build_windows() -> io::Result<()> {
+    let data = create_hex_lines(5050);
+    let compressed_data = compress(&data, Gzip as i32)?;
+    let index = build_index(&mut Cursor::new(&compressed_data), 4096)?;
+    assert!(index.list.len() > 10);
+
+    // Every window holds exactly the data before its point
+    for point in &index.list {
+        let out = point.out as usize;
+        let mut window = vec![0; 32768usize.saturating_sub(out)];
+        window.extend_from_slice(&data[out.saturating_sub(32768)..out]);
+        assert_eq!(point.window, window, "window at {}", out);
+    }
+
+    Ok(())
+}
+
+#[test]
+pub fn test_thin_writer_index() -> io::Result<()> {
+    let data = create_lines(30000);
+    for split in [SplitMode::FullFlush, SplitMode::Members] {
+        let mut writer = SeekableGzWriter::with_options(vec![], 20000, 6, split)?;
+        writer.write_all(&data)?;
+        let (compressed_data, index) = writer.finish()?;
+
+        // Window-free points are thinned like any other
+        let thinned = index.thin(100_000);
+        assert!(thinned.list.len() > 1);
+        assert!(thinned.list.len() * 4 < index.list.len());
+        for pair in thinned.list.windows(2) {
+            assert!(pair[1].out - pair[0].out >= 100_000);
+        }
+
+        let mut reader = SeekableZLibReader::new(Cursor::new(&compressed_data), thinned);
+        let mut buffer = vec![0; 1000];
+        for offset in [0, 123_456, data.len() as u64 - 1000] {
+            reader.seek(SeekFrom::Start(offset))?;
+            reader.read_exact(&mut buffer)?;
+            assert_eq!(buffer, &data[offset as usize..offset as usize + 1000]);
+        }
+    }
+
+    Ok(())
+}
+
+// Source whose reads panic, standing in for a bug in a worker
+struct PanickingSource(u64);
+
+impl RandomAccessSource for PanickingSource {
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.0)
+    }
+
+    fn read_at(&self, _offset: u64, _buf: &mut [u8]) -> io::Result<usize> {
+        panic!("read failed");
+    }
+}
+
+#[test]
+pub fn test_search_worker_panic() -> io::Result<()> {
+    let data = create_lines(1000);
+    let compressed_data = compress(&data, Gzip as i32)?;
+    let index = build_index(&mut Cursor::new(&compressed_data), CHUNK as u64)?;
+
+    // The panic is reported as an error to the caller
+    let source = PanickingSource(compressed_data.len() as u64);
+    let err = search(&source, &index, b"line", 2).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::Other);
+
+    Ok(())
+}
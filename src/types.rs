@@ -1,21 +1,37 @@
 use byteorder::BigEndian;
-use byteorder::WriteBytesExt;
-use std::io::Write;
+use byteorder::{ReadBytesExt, WriteBytesExt};
+use std::fmt;
+use std::io::{self, Read, Write};
 
 pub const WINSIZE: usize = 32768;
 pub const CHUNK: usize = 16384;
+pub const SPAN: u64 = 1048576;
+const MAX_FINGERPRINT: usize = 1024; // bytes of a serialized fingerprint
 
+// A serialized index starts with the magic and the format version
+const MAGIC: [u8; 4] = *b"ZRAN";
+const VERSION: u16 = 1;
+
+/// Format of the compressed data. The values are the window bits that select
+/// it in zlib.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
 pub enum CompressionMode {
+    /// Detect the format from the header when building an index
+    #[default]
+    Auto = 0,
     Raw = -15,
     Zlib = 15,
     Gzip = 31,
 }
 
-#[derive(Debug, Clone, Default)]
+#[derive(Clone, Default)]
 pub struct Point {
     pub inn: u64,
     pub out: u64,
     pub bits: u32,
+    pub lines: u64,   // delimiters before out, when the index counts lines
+    pub record: u64,  // first record start at or after out
+    pub resync: bool, // decoding resumed here after damaged data
     pub window: Vec<u8>,
 }
 
@@ -25,28 +41,140 @@ impl Point {
             inn: 0,
             out: 0,
             bits: 0,
+            lines: 0,
+            record: 0,
+            resync: false,
             window: vec![0; WINSIZE],
         }
     }
 }
 
+// Shows the size of the window rather than its contents
+impl fmt::Debug for Point {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Point")
+            .field("inn", &self.inn)
+            .field("out", &self.out)
+            .field("bits", &self.bits)
+            .field("lines", &self.lines)
+            .field("record", &self.record)
+            .field("resync", &self.resync)
+            .field("window", &format_args!("<{} bytes>", self.window.len()))
+            .finish()
+    }
+}
+
+/// A record-aligned slice of the uncompressed data, decodable starting at
+/// access point `point`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Shard {
+    pub point: usize,
+    pub start: u64,
+    pub end: u64,
+}
+
 #[derive(Debug, Clone, Default)]
 pub struct DeflateIndex {
-    pub mode: i32,
+    pub mode: CompressionMode,
     pub list: Vec<Point>,
     pub length: u64,
+    pub data_end: u64, // compressed offset where the indexed data ends, 0 if unknown
+    pub delimiter: Option<u8>, // line delimiter counted while indexing
+    pub lines: u64,    // total delimiters in the uncompressed data
+    pub window_size: usize, // history kept per access point, at most WINSIZE
+    pub members: u64,  // gzip members, 0 for other formats
+    pub zero_padding: bool, // zero bytes between gzip members are skipped
+    /// `RandomAccessSource::fingerprint` of the data the index was built
+    /// from. Decoding from a source with another fingerprint fails.
+    pub fingerprint: Option<String>,
+}
+
+/// Summary of an index, see `DeflateIndex::stats`. A span is the data from
+/// an access point to the next one, or to the end.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IndexStats {
+    pub points: usize,
+    pub length: u64,
+    pub compressed_length: u64,
+    pub window_bytes: u64, // total over all points
+    pub average_span: u64,
+    pub max_span: u64,
+    pub average_compressed_span: u64,
+    pub max_compressed_span: u64,
+    /// Bytes read and inflated in the worst case to reach an offset: the
+    /// compressed and uncompressed size of the largest span.
+    pub worst_seek_cost: u64,
+    pub span_ratios: Vec<f64>, // uncompressed per compressed bytes of each span
+    pub members: u64,
 }
 
 impl DeflateIndex {
     pub fn new() -> Self {
         Self {
-            mode: 0,
+            mode: CompressionMode::Auto,
             list: vec![],
             length: 0,
+            data_end: 0,
+            delimiter: None,
+            lines: 0,
+            window_size: WINSIZE,
+            members: 0,
+            zero_padding: false,
+            fingerprint: None,
         }
     }
 
-    pub fn add_point(&mut self, bits: u32, inn: u64, out: u64, left: usize, window: &[u8]) {
+    /// Sizes of the spans between access points and other figures for
+    /// capacity planning.
+    pub fn stats(&self) -> IndexStats {
+        // (compressed, uncompressed) size of every span
+        let spans: Vec<(u64, u64)> = self
+            .list
+            .iter()
+            .enumerate()
+            .map(|(k, point)| match self.list.get(k + 1) {
+                Some(next) => (
+                    next.inn.saturating_sub(point.inn),
+                    next.out.saturating_sub(point.out),
+                ),
+                None => (
+                    self.data_end.saturating_sub(point.inn),
+                    self.length.saturating_sub(point.out),
+                ),
+            })
+            .collect();
+        let count = std::cmp::max(spans.len(), 1) as u64;
+
+        IndexStats {
+            points: self.list.len(),
+            length: self.length,
+            compressed_length: self.data_end,
+            window_bytes: self
+                .list
+                .iter()
+                .map(|point| point.window.len() as u64)
+                .sum(),
+            average_span: spans.iter().map(|span| span.1).sum::<u64>() / count,
+            max_span: spans.iter().map(|span| span.1).max().unwrap_or(0),
+            average_compressed_span: spans.iter().map(|span| span.0).sum::<u64>() / count,
+            max_compressed_span: spans.iter().map(|span| span.0).max().unwrap_or(0),
+            worst_seek_cost: spans.iter().map(|span| span.0 + span.1).max().unwrap_or(0),
+            span_ratios: spans
+                .iter()
+                .map(|&(inn, out)| out as f64 / std::cmp::max(inn, 1) as f64)
+                .collect(),
+            members: self.members,
+        }
+    }
+
+    pub fn add_point(
+        &mut self,
+        bits: u32,
+        inn: u64,
+        out: u64,
+        left: usize,
+        window: &[u8],
+    ) -> &mut Point {
         let mut point = Point::new();
         point.inn = inn;
         point.out = out;
@@ -59,22 +187,275 @@ impl DeflateIndex {
         if left < WINSIZE {
             point.window[left..].copy_from_slice(&window[..WINSIZE - left]);
         }
+        // The data cannot refer back further than the window of the stream
+        let size = std::cmp::min(self.window_size, WINSIZE);
+        point.window.drain(..WINSIZE - size);
 
         self.list.push(point);
+        self.list.last_mut().unwrap()
+    }
+
+    /// Returns a copy keeping only access points at least `span` uncompressed
+    /// bytes apart, without any I/O. The first point is always kept, and so
+    /// are the points where `IndexBuilder::build_tolerant` resumed after
+    /// damage, since the points before them cannot decode that far.
+    pub fn thin(&self, span: u64) -> DeflateIndex {
+        let mut list: Vec<Point> = vec![];
+        for point in &self.list {
+            if list
+                .last()
+                .map_or(true, |last| point.resync || point.out - last.out >= span)
+            {
+                list.push(point.clone());
+            }
+        }
+        self.with_points(list)
+    }
+
+    /// Returns the access points needed to read the uncompressed range
+    /// `start..end`: the one before `start` and those up to `end`. Offsets
+    /// are kept, so the slice reads the range from the same source and can
+    /// be merged back.
+    pub fn slice(&self, start: u64, end: u64) -> DeflateIndex {
+        let first = self.locate(start).unwrap_or(0);
+        let last = self.list.partition_point(|point| point.out < end);
+        let last = std::cmp::min(std::cmp::max(last, first + 1), self.list.len());
+        self.with_points(self.list[first..last].to_vec())
+    }
+
+    /// Combines the access points of two indexes of the same data, such as a
+    /// thinned index and a denser slice of it.
+    pub fn merge(&self, other: &DeflateIndex) -> io::Result<DeflateIndex> {
+        if self.mode != other.mode
+            || self.length != other.length
+            || self.data_end != other.data_end
+            || self.delimiter != other.delimiter
+            || self.window_size != other.window_size
+            || self.fingerprint != other.fingerprint
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Indexes of different data",
+            ));
+        }
+
+        let mut list: Vec<Point> = self.list.iter().chain(&other.list).cloned().collect();
+        list.sort_by_key(|point| point.out); // stable, so self wins ties
+        list.dedup_by_key(|point| point.out);
+        Ok(self.with_points(list))
+    }
+
+    // An index of the same data with other access points
+    pub(crate) fn with_points(&self, list: Vec<Point>) -> DeflateIndex {
+        DeflateIndex {
+            mode: self.mode,
+            list,
+            length: self.length,
+            data_end: self.data_end,
+            delimiter: self.delimiter,
+            lines: self.lines,
+            window_size: self.window_size,
+            members: self.members,
+            zero_padding: self.zero_padding,
+            fingerprint: self.fingerprint.clone(),
+        }
+    }
+
+    /// Returns the position in `list` of the access point closest to but not
+    /// after `offset`.
+    pub fn locate(&self, offset: u64) -> Option<usize> {
+        self.list
+            .partition_point(|point| point.out <= offset)
+            .checked_sub(1)
+    }
+
+    /// Splits the uncompressed data into at most `count` shards of roughly
+    /// equal size. Each shard starts at the first record after an access
+    /// point, so workers can decode shards independently. Without a
+    /// delimiter every access point counts as a record start.
+    pub fn record_shards(&self, count: usize) -> Vec<Shard> {
+        let mut shards: Vec<Shard> = vec![];
+        if self.list.is_empty() || count == 0 {
+            return shards;
+        }
+
+        for k in 0..count {
+            let target = (self.length as u128 * k as u128 / count as u128) as u64;
+            let point = self.locate(target).unwrap_or(0);
+            let start = if k == 0 { 0 } else { self.list[point].record };
+
+            let next = shards.last().map_or(true, |shard| start > shard.start);
+            if next && (k == 0 || start < self.length) {
+                if let Some(shard) = shards.last_mut() {
+                    shard.end = start;
+                }
+                shards.push(Shard {
+                    point,
+                    start,
+                    end: self.length,
+                });
+            }
+        }
+
+        shards
     }
 
     pub fn serialize(&self, writer: &mut dyn Write) -> std::io::Result<()> {
+        writer.write_all(&MAGIC)?;
+        writer.write_u16::<BigEndian>(VERSION)?;
         writer.write_u64::<BigEndian>(self.length)?;
-        writer.write_i32::<BigEndian>(self.mode)?;
+        writer.write_i32::<BigEndian>(self.mode as i32)?;
         writer.write_i32::<BigEndian>(self.list.len() as i32)?;
+        writer.write_i32::<BigEndian>(self.delimiter.map_or(-1, i32::from))?;
+        writer.write_u64::<BigEndian>(self.lines)?;
+        writer.write_u32::<BigEndian>(self.window_size as u32)?;
+        writer.write_u64::<BigEndian>(self.data_end)?;
+        writer.write_u64::<BigEndian>(self.members)?;
+        writer.write_u8(u8::from(self.zero_padding))?;
+        let fingerprint = self.fingerprint.as_deref().unwrap_or("");
+        if fingerprint.len() > MAX_FINGERPRINT {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Fingerprint too long",
+            ));
+        }
+        writer.write_u16::<BigEndian>(fingerprint.len() as u16)?;
+        writer.write_all(fingerprint.as_bytes())?;
 
         for point in &self.list {
             writer.write_u64::<BigEndian>(point.inn)?;
             writer.write_u64::<BigEndian>(point.out)?;
             writer.write_u32::<BigEndian>(point.bits)?;
+            writer.write_u64::<BigEndian>(point.lines)?;
+            writer.write_u64::<BigEndian>(point.record)?;
+            writer.write_u8(u8::from(point.resync))?;
+            writer.write_u32::<BigEndian>(point.window.len() as u32)?;
             writer.write_all(&point.window)?;
         }
 
         Ok(())
     }
+
+    pub fn deserialize(reader: &mut dyn Read) -> io::Result<Self> {
+        let invalid = |msg| io::Error::new(io::ErrorKind::InvalidData, msg);
+
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if magic != MAGIC {
+            return Err(invalid("Not a zran index"));
+        }
+        let version = reader.read_u16::<BigEndian>()?;
+        if version != VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Unsupported index format version {}", version),
+            ));
+        }
+
+        let mut index = DeflateIndex::new();
+        index.length = reader.read_u64::<BigEndian>()?;
+        let mode = reader.read_i32::<BigEndian>()?;
+        let count = reader.read_i32::<BigEndian>()?;
+        let delimiter = reader.read_i32::<BigEndian>()?;
+        index.lines = reader.read_u64::<BigEndian>()?;
+        index.window_size = reader.read_u32::<BigEndian>()? as usize;
+        index.data_end = reader.read_u64::<BigEndian>()?;
+        index.members = reader.read_u64::<BigEndian>()?;
+        index.zero_padding = match reader.read_u8()? {
+            0 => false,
+            1 => true,
+            _ => return Err(invalid("Invalid zero padding flag")),
+        };
+        let fingerprint = reader.read_u16::<BigEndian>()? as usize;
+        if fingerprint > MAX_FINGERPRINT {
+            return Err(invalid("Invalid fingerprint"));
+        }
+        let mut bytes = vec![0; fingerprint];
+        reader.read_exact(&mut bytes)?;
+        if !bytes.is_empty() {
+            let fingerprint =
+                String::from_utf8(bytes).map_err(|_| invalid("Invalid fingerprint"))?;
+            index.fingerprint = Some(fingerprint);
+        }
+
+        index.mode = match mode {
+            0 => CompressionMode::Auto,
+            -15 => CompressionMode::Raw,
+            15 => CompressionMode::Zlib,
+            31 => CompressionMode::Gzip,
+            _ => return Err(invalid("Invalid compression mode")),
+        };
+        if count < 0 {
+            return Err(invalid("Invalid access point count"));
+        }
+        index.delimiter = match delimiter {
+            -1 => None,
+            0..=255 => Some(delimiter as u8),
+            _ => return Err(invalid("Invalid line delimiter")),
+        };
+        if index.window_size > WINSIZE {
+            return Err(invalid("Invalid window size"));
+        }
+
+        for _ in 0..count {
+            let mut point = Point {
+                inn: reader.read_u64::<BigEndian>()?,
+                out: reader.read_u64::<BigEndian>()?,
+                bits: reader.read_u32::<BigEndian>()?,
+                lines: reader.read_u64::<BigEndian>()?,
+                record: reader.read_u64::<BigEndian>()?,
+                resync: match reader.read_u8()? {
+                    0 => false,
+                    1 => true,
+                    _ => return Err(invalid("Invalid access point")),
+                },
+                window: vec![],
+            };
+            let window = reader.read_u32::<BigEndian>()? as usize;
+            // A point inside a byte needs the byte before it
+            if point.bits > 7 || (point.bits != 0 && point.inn == 0) || window > index.window_size {
+                return Err(invalid("Invalid access point"));
+            }
+            if point.out > index.length || (index.data_end != 0 && point.inn > index.data_end) {
+                return Err(invalid("Access point past the end of the data"));
+            }
+            if let Some(last) = index.list.last() {
+                if point.inn < last.inn || point.out < last.out {
+                    return Err(invalid("Access points out of order"));
+                }
+            }
+            point.window = vec![0; window];
+            reader.read_exact(&mut point.window)?;
+            index.list.push(point);
+        }
+
+        Ok(index)
+    }
+}
+
+// A one line summary, e.g. for logs
+impl fmt::Display for DeflateIndex {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mode = match self.mode {
+            CompressionMode::Auto => "unknown",
+            CompressionMode::Raw => "raw deflate",
+            CompressionMode::Zlib => "zlib",
+            CompressionMode::Gzip => "gzip",
+        };
+        write!(
+            f,
+            "{} index: {} bytes from {} compressed, {} access points",
+            mode,
+            self.length,
+            self.data_end,
+            self.list.len()
+        )?;
+        if self.mode == CompressionMode::Gzip {
+            write!(f, ", {} members", self.members)?;
+        }
+        if let Some(delimiter) = self.delimiter {
+            write!(f, ", {} lines (delimiter {:#04x})", self.lines, delimiter)?;
+        }
+        Ok(())
+    }
 }
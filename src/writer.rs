@@ -0,0 +1,247 @@
+use std::io::{self, Write};
+use std::ops::{Deref, DerefMut};
+
+use libz_rs_sys::{
+    deflate, deflateEnd, deflateInit2_, deflateReset, z_stream, zlibVersion, Z_BUF_ERROR,
+    Z_DEFAULT_STRATEGY, Z_DEFLATED, Z_FINISH, Z_FULL_FLUSH, Z_NO_FLUSH, Z_OK, Z_STREAM_END,
+    Z_SYNC_FLUSH,
+};
+
+use crate::embed::append_index;
+use crate::types::{CompressionMode, DeflateIndex, Point, CHUNK};
+use crate::zran::{new_z_stream, zlib_error_description};
+
+/// Length of the gzip header deflate writes when no gz_header is set.
+const GZIP_HEADER_LEN: u64 = 10;
+
+/// How a `SeekableGzWriter` makes the stream seekable every span.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SplitMode {
+    /// Issue a `Z_FULL_FLUSH`, which byte-aligns the output and resets the
+    /// sliding window.
+    FullFlush,
+    /// End the gzip member and start a new one.
+    Members,
+}
+
+/// Gzip compressor that makes the stream independently decodable every
+/// `span` uncompressed bytes and records a window-free access point there.
+///
+/// The output is standard gzip. Call `finish` to write the trailer and get
+/// the matching `DeflateIndex`.
+pub struct SeekableGzWriter<W: Write> {
+    inner: W,
+    stream: Deflater,
+    output: Vec<u8>,
+    split: SplitMode,
+    span: u64,
+    since_point: u64, // uncompressed bytes since the last access point
+    total_in: u64,
+    total_out: u64,
+    index: DeflateIndex,
+}
+
+impl<W: Write> SeekableGzWriter<W> {
+    pub fn new(inner: W, span: u64) -> io::Result<Self> {
+        Self::with_options(inner, span, 6, SplitMode::FullFlush)
+    }
+
+    pub fn with_options(inner: W, span: u64, level: i32, split: SplitMode) -> io::Result<Self> {
+        let mut writer = Self {
+            inner,
+            stream: Deflater::new(level, CompressionMode::Gzip as i32)?,
+            output: vec![0; CHUNK],
+            split,
+            span: std::cmp::max(span, 1),
+            since_point: 0,
+            total_in: 0,
+            total_out: 0,
+            index: DeflateIndex::new(),
+        };
+
+        writer.index.mode = CompressionMode::Gzip;
+        writer.index.members = 1;
+        writer.add_point(GZIP_HEADER_LEN);
+        Ok(writer)
+    }
+
+    /// Access points recorded so far.
+    pub fn index(&self) -> &DeflateIndex {
+        &self.index
+    }
+
+    /// Writes the gzip trailer and returns the inner writer together with
+    /// the index of the stream.
+    pub fn finish(self) -> io::Result<(W, DeflateIndex)> {
+        let (inner, index, _) = self.finish_stream()?;
+        Ok((inner, index))
+    }
+
+    fn finish_stream(mut self) -> io::Result<(W, DeflateIndex, u64)> {
+        self.deflate(Z_FINISH)?;
+        self.index.length = self.total_in;
+        self.index.data_end = self.total_out;
+        self.inner.flush()?;
+
+        let Self {
+            inner,
+            index,
+            total_out,
+            ..
+        } = self;
+        Ok((inner, index, total_out))
+    }
+
+    /// Like `finish`, but also appends the index to the stream as trailing
+    /// gzip members, so `SeekableZLibReader::open` can use it directly.
+    pub fn finish_embedded(self) -> io::Result<(W, DeflateIndex)> {
+        let (mut inner, index, total_out) = self.finish_stream()?;
+        append_index(&mut inner, &index, total_out)?;
+        inner.flush()?;
+        Ok((inner, index))
+    }
+
+    fn add_point(&mut self, inn: u64) {
+        self.index.list.push(Point {
+            inn,
+            out: self.total_in,
+            bits: 0,
+            lines: 0,
+            record: self.total_in,
+            resync: false,
+            window: vec![],
+        });
+        self.since_point = 0;
+    }
+
+    // Makes the stream decodable from the current position without history.
+    fn split(&mut self) -> io::Result<()> {
+        match self.split {
+            SplitMode::FullFlush => {
+                self.deflate(Z_FULL_FLUSH)?;
+                self.add_point(self.total_out);
+            }
+            SplitMode::Members => {
+                self.deflate(Z_FINISH)?;
+                let ret = unsafe { deflateReset(&mut *self.stream) };
+                if ret != Z_OK {
+                    return Err(deflate_error(ret));
+                }
+                self.add_point(self.total_out + GZIP_HEADER_LEN);
+                self.index.members += 1;
+            }
+        }
+        Ok(())
+    }
+
+    // Runs deflate on the pending input until it is consumed and, for a
+    // flush, until all output is written.
+    fn deflate(&mut self, flush: i32) -> io::Result<()> {
+        loop {
+            self.stream.avail_out = self.output.len() as u32;
+            self.stream.next_out = self.output.as_mut_ptr();
+
+            let ret = unsafe { deflate(&mut *self.stream, flush) };
+            if ret != Z_OK && ret != Z_STREAM_END && ret != Z_BUF_ERROR {
+                return Err(deflate_error(ret));
+            }
+
+            let have = self.output.len() - self.stream.avail_out as usize;
+            self.inner.write_all(&self.output[..have])?;
+            self.total_out += have as u64;
+
+            let done = match flush {
+                Z_FINISH => ret == Z_STREAM_END,
+                _ => self.stream.avail_in == 0 && self.stream.avail_out != 0,
+            };
+            if done {
+                return Ok(());
+            }
+        }
+    }
+}
+
+impl<W: Write> Write for SeekableGzWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut written = 0;
+        while written < buf.len() {
+            // Split lazily so that no access point is added at the very end
+            if self.since_point >= self.span {
+                self.split()?;
+            }
+
+            let take = std::cmp::min(
+                (buf.len() - written) as u64,
+                std::cmp::min(self.span - self.since_point, u32::MAX as u64),
+            ) as usize;
+            self.stream.next_in = buf[written..].as_ptr() as *mut u8;
+            self.stream.avail_in = take as u32;
+            self.deflate(Z_NO_FLUSH)?;
+
+            written += take;
+            self.since_point += take as u64;
+            self.total_in += take as u64;
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.deflate(Z_SYNC_FLUSH)?;
+        self.inner.flush()
+    }
+}
+
+// Owns a deflate state and releases it on drop. Boxed because zlib keeps a
+// pointer back to the stream.
+pub(crate) struct Deflater(Box<z_stream>);
+
+impl Deflater {
+    pub(crate) fn new(level: i32, window_bits: i32) -> io::Result<Self> {
+        let mut stream = Deflater(Box::new(new_z_stream()));
+        let ret = unsafe {
+            deflateInit2_(
+                &mut *stream,
+                level,
+                Z_DEFLATED,
+                window_bits,
+                8,
+                Z_DEFAULT_STRATEGY,
+                zlibVersion(),
+                std::mem::size_of::<z_stream>() as i32,
+            )
+        };
+        if ret != Z_OK {
+            return Err(deflate_error(ret));
+        }
+        Ok(stream)
+    }
+}
+
+impl Deref for Deflater {
+    type Target = z_stream;
+
+    fn deref(&self) -> &z_stream {
+        &self.0
+    }
+}
+
+impl DerefMut for Deflater {
+    fn deref_mut(&mut self) -> &mut z_stream {
+        &mut self.0
+    }
+}
+
+impl Drop for Deflater {
+    fn drop(&mut self) {
+        unsafe {
+            deflateEnd(&mut *self.0);
+        }
+    }
+}
+
+pub(crate) fn deflate_error(ret: i32) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Other,
+        format!("deflate error: {}", zlib_error_description(ret)),
+    )
+}
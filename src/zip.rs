@@ -0,0 +1,245 @@
+// Random access into the deflated entries of a zip archive. The data of each
+// entry is a raw deflate stream at an offset given by the central directory,
+// so it is indexed and read like any other stream through a `RangeSource`.
+
+use std::io::{self, Cursor};
+
+use byteorder::{LittleEndian, ReadBytesExt};
+
+use crate::reader::SeekableZLibReader;
+use crate::source::{RandomAccessSource, RangeSource, SourceReader};
+use crate::types::{CompressionMode, DeflateIndex};
+use crate::zran::IndexBuilder;
+
+const EOCD_SIGNATURE: u32 = 0x06054b50;
+const EOCD_LEN: usize = 22;
+const ZIP64_LOCATOR_SIGNATURE: u32 = 0x07064b50;
+const ZIP64_EOCD_SIGNATURE: u32 = 0x06064b50;
+const CENTRAL_SIGNATURE: u32 = 0x02014b50;
+const LOCAL_SIGNATURE: u32 = 0x04034b50;
+const MAX_COMMENT: usize = 65535;
+
+/// Compression method of deflated entries.
+pub const DEFLATED: u16 = 8;
+
+/// An entry of the central directory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ZipEntry {
+    pub name: String,
+    pub method: u16, // 0 for stored, 8 for deflated
+    pub flags: u16,
+    pub crc32: u32,
+    pub compressed_size: u64,
+    pub size: u64,
+    pub header_offset: u64, // offset of the local file header
+}
+
+/// A zip archive whose deflated entries can be read with random access.
+pub struct ZipArchive<S: RandomAccessSource> {
+    source: S,
+    entries: Vec<ZipEntry>,
+}
+
+impl<S: RandomAccessSource> ZipArchive<S> {
+    /// Reads the central directory of the archive in `source`.
+    pub fn new(source: S) -> io::Result<Self> {
+        let entries = read_central_directory(&source)?;
+        Ok(Self { source, entries })
+    }
+
+    pub fn entries(&self) -> &[ZipEntry] {
+        &self.entries
+    }
+
+    pub fn entry(&self, name: &str) -> Option<&ZipEntry> {
+        self.entries.iter().find(|entry| entry.name == name)
+    }
+
+    /// Builds the index of the deflated entry `name`. The offsets in the
+    /// index are relative to the start of the entry data.
+    pub fn build_index(&self, name: &str, builder: &IndexBuilder) -> io::Result<DeflateIndex> {
+        let data = self.entry_data(name)?;
+        let index = builder
+            .clone()
+            .mode(CompressionMode::Raw)
+            .build(&mut SourceReader::new(&data))?;
+        if index.length != self.entry(name).unwrap().size {
+            return Err(invalid("Entry size does not match its data"));
+        }
+        Ok(index)
+    }
+
+    /// Opens the deflated entry `name` for reading with an index built by
+    /// `build_index`, or loaded from an earlier run.
+    pub fn open_entry(
+        &self,
+        name: &str,
+        index: DeflateIndex,
+    ) -> io::Result<SeekableZLibReader<RangeSource<&S>>> {
+        let data = self.entry_data(name)?;
+        if index.mode != CompressionMode::Raw || index.length != self.entry(name).unwrap().size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Index does not match the entry",
+            ));
+        }
+        Ok(SeekableZLibReader::from_source(data, index))
+    }
+
+    // The compressed data of the entry, found after its local header
+    fn entry_data(&self, name: &str) -> io::Result<RangeSource<&S>> {
+        let entry = self.entry(name).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No entry {} in the archive", name),
+            )
+        })?;
+        if entry.method != DEFLATED {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Entry {} is not deflated", name),
+            ));
+        }
+        if entry.flags & 1 != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Entry {} is encrypted", name),
+            ));
+        }
+
+        let mut header = [0; 30];
+        self.source
+            .read_exact_at(entry.header_offset, &mut header)?;
+        let mut header = Cursor::new(&header[..]);
+        if header.read_u32::<LittleEndian>()? != LOCAL_SIGNATURE {
+            return Err(invalid("Invalid local file header"));
+        }
+        header.set_position(26);
+        let name_len = header.read_u16::<LittleEndian>()? as u64;
+        let extra_len = header.read_u16::<LittleEndian>()? as u64;
+
+        let start = entry.header_offset + 30 + name_len + extra_len;
+        Ok(RangeSource::new(&self.source, start, entry.compressed_size))
+    }
+}
+
+fn read_central_directory<S: RandomAccessSource + ?Sized>(source: &S) -> io::Result<Vec<ZipEntry>> {
+    // The end of central directory record is followed only by a comment
+    let len = source.len()?;
+    let tail_len = std::cmp::min(len, (EOCD_LEN + MAX_COMMENT) as u64) as usize;
+    let mut tail = vec![0; tail_len];
+    source.read_exact_at(len - tail_len as u64, &mut tail)?;
+    let eocd = (0..tail_len.saturating_sub(EOCD_LEN - 1))
+        .rev()
+        .find(|&i| tail[i..i + 4] == EOCD_SIGNATURE.to_le_bytes())
+        .ok_or_else(|| invalid("No end of central directory record"))?;
+    let eocd_offset = len - (tail_len - eocd) as u64;
+
+    let mut record = Cursor::new(&tail[eocd + 10..eocd + EOCD_LEN]);
+    let mut count = record.read_u16::<LittleEndian>()? as u64;
+    let mut size = record.read_u32::<LittleEndian>()? as u64;
+    let mut offset = record.read_u32::<LittleEndian>()? as u64;
+
+    if count == 0xffff || size == 0xffff_ffff || offset == 0xffff_ffff {
+        // Zip64: the locator in front of the record points to the real one
+        let mut locator = [0; 20];
+        source.read_exact_at(
+            eocd_offset
+                .checked_sub(20)
+                .ok_or_else(|| invalid("Missing zip64 locator"))?,
+            &mut locator,
+        )?;
+        let mut locator = Cursor::new(&locator[..]);
+        if locator.read_u32::<LittleEndian>()? != ZIP64_LOCATOR_SIGNATURE {
+            return Err(invalid("Missing zip64 locator"));
+        }
+        locator.set_position(8);
+        let record_offset = locator.read_u64::<LittleEndian>()?;
+
+        let mut record = [0; 56];
+        source.read_exact_at(record_offset, &mut record)?;
+        let mut record = Cursor::new(&record[..]);
+        if record.read_u32::<LittleEndian>()? != ZIP64_EOCD_SIGNATURE {
+            return Err(invalid("Invalid zip64 end of central directory record"));
+        }
+        record.set_position(32);
+        count = record.read_u64::<LittleEndian>()?;
+        size = record.read_u64::<LittleEndian>()?;
+        offset = record.read_u64::<LittleEndian>()?;
+    }
+
+    // The central directory lies before the record, which bounds its size
+    if offset
+        .checked_add(size)
+        .map_or(true, |end| end > eocd_offset)
+    {
+        return Err(invalid("Invalid central directory size or offset"));
+    }
+    let mut directory = vec![0; size as usize];
+    source.read_exact_at(offset, &mut directory)?;
+    let mut directory = Cursor::new(&directory[..]);
+    let mut entries = vec![];
+    for _ in 0..count {
+        entries.push(read_central_entry(&mut directory)?);
+    }
+    Ok(entries)
+}
+
+fn read_central_entry(directory: &mut Cursor<&[u8]>) -> io::Result<ZipEntry> {
+    if directory.read_u32::<LittleEndian>()? != CENTRAL_SIGNATURE {
+        return Err(invalid("Invalid central directory entry"));
+    }
+    directory.set_position(directory.position() + 4); // versions
+    let flags = directory.read_u16::<LittleEndian>()?;
+    let method = directory.read_u16::<LittleEndian>()?;
+    directory.set_position(directory.position() + 4); // time and date
+    let crc32 = directory.read_u32::<LittleEndian>()?;
+    let mut compressed_size = directory.read_u32::<LittleEndian>()? as u64;
+    let mut size = directory.read_u32::<LittleEndian>()? as u64;
+    let name_len = directory.read_u16::<LittleEndian>()? as usize;
+    let extra_len = directory.read_u16::<LittleEndian>()? as usize;
+    let comment_len = directory.read_u16::<LittleEndian>()? as usize;
+    directory.set_position(directory.position() + 8); // disk and attributes
+    let mut header_offset = directory.read_u32::<LittleEndian>()? as u64;
+
+    let start = directory.position() as usize;
+    let data = *directory.get_ref();
+    if data.len() < start + name_len + extra_len + comment_len {
+        return Err(invalid("Truncated central directory"));
+    }
+    let name = String::from_utf8_lossy(&data[start..start + name_len]).into_owned();
+
+    // The zip64 extra field holds the values that did not fit, in order
+    let mut extra = &data[start + name_len..start + name_len + extra_len];
+    while extra.len() >= 4 {
+        let id = u16::from_le_bytes([extra[0], extra[1]]);
+        let len = std::cmp::min(
+            u16::from_le_bytes([extra[2], extra[3]]) as usize,
+            extra.len() - 4,
+        );
+        if id == 1 {
+            let mut field = Cursor::new(&extra[4..4 + len]);
+            for value in [&mut size, &mut compressed_size, &mut header_offset] {
+                if *value == 0xffff_ffff {
+                    *value = field.read_u64::<LittleEndian>()?;
+                }
+            }
+        }
+        extra = &extra[4 + len..];
+    }
+
+    directory.set_position((start + name_len + extra_len + comment_len) as u64);
+    Ok(ZipEntry {
+        name,
+        method,
+        flags,
+        crc32,
+        compressed_size,
+        size,
+        header_offset,
+    })
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
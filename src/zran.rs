@@ -6,10 +6,16 @@ use libz_rs_sys::{
     Z_STREAM_END, Z_STREAM_ERROR,
 };
 
+use crate::embed::{is_index_member, MEMBER_PEEK};
 use crate::pushback::PushbackReader;
-use crate::types::{CompressionMode, DeflateIndex, CHUNK, WINSIZE};
+use crate::source::{RandomAccessSource, SourceReader};
+use crate::types::{CompressionMode, DeflateIndex, Point, CHUNK, SPAN, WINSIZE};
 
-fn fread<R: Read>(reader: &mut R, buffer: &mut [u8], length: usize) -> io::Result<usize> {
+pub(crate) fn fread<R: Read>(
+    reader: &mut R,
+    buffer: &mut [u8],
+    length: usize,
+) -> io::Result<usize> {
     let mut total_read = 0;
     while total_read < length {
         match reader.read(&mut buffer[total_read..length])? {
@@ -20,7 +26,81 @@ fn fread<R: Read>(reader: &mut R, buffer: &mut [u8], length: usize) -> io::Resul
     Ok(total_read)
 }
 
-fn new_z_stream() -> z_stream {
+// Makes at least `want` bytes of input available at next_in, unless the
+// input ends first, by moving the unused input to the start of the buffer.
+unsafe fn fill_ahead<R: Read>(
+    stream: &mut z_stream,
+    buffer: &mut [u8],
+    reader: &mut R,
+    totin: &mut u64,
+    want: usize,
+) -> io::Result<()> {
+    let avail = stream.avail_in as usize;
+    if avail >= want {
+        return Ok(());
+    }
+    if avail > 0 {
+        let start = stream.next_in.offset_from(buffer.as_ptr()) as usize;
+        buffer.copy_within(start..start + avail, 0);
+    }
+    let space = buffer.len() - avail;
+    let n = fread(reader, &mut buffer[avail..], space)?;
+    *totin += n as u64;
+    stream.avail_in = (avail + n) as u32;
+    stream.next_in = buffer.as_mut_ptr();
+    Ok(())
+}
+
+// Determines the type of the input at next_in from its header: gzip needs
+// the magic, the deflate method and no reserved flags, zlib the deflate
+// method, a valid window size and a matching FCHECK. Anything else is taken
+// as raw deflate, which can't start like gzip, as that would be a block of
+// the reserved type, and only starts like zlib with fill bits that deflate
+// never sets.
+pub(crate) unsafe fn detect_mode(stream: &z_stream) -> CompressionMode {
+    if stream.avail_in == 0 {
+        return CompressionMode::Raw; // empty -- will fail
+    }
+    let header = std::slice::from_raw_parts(stream.next_in, stream.avail_in as usize);
+    match *header {
+        _ if is_gzip_header(header) => CompressionMode::Gzip,
+        [cmf, flg, ..]
+            if cmf & 0xf == 8 && cmf >> 4 <= 7 && (cmf as u16 * 256 + flg as u16) % 31 == 0 =>
+        {
+            CompressionMode::Zlib
+        }
+        _ => CompressionMode::Raw,
+    }
+}
+
+fn is_gzip_header(data: &[u8]) -> bool {
+    matches!(*data, [0x1f, 0x8b, 8, flags, ..] if flags & 0xe0 == 0)
+}
+
+// Skips zero bytes at next_in, reading on until other data or the end of the
+// input.
+unsafe fn skip_zeros<R: Read>(
+    stream: &mut z_stream,
+    buffer: &mut [u8],
+    reader: &mut R,
+    totin: &mut u64,
+) -> io::Result<()> {
+    loop {
+        while stream.avail_in > 0 && *stream.next_in == 0 {
+            stream.next_in = stream.next_in.add(1);
+            stream.avail_in -= 1;
+        }
+        if stream.avail_in > 0 {
+            return Ok(());
+        }
+        fill_ahead(stream, buffer, reader, totin, 1)?;
+        if stream.avail_in == 0 {
+            return Ok(());
+        }
+    }
+}
+
+pub(crate) fn new_z_stream() -> z_stream {
     z_stream {
         next_in: std::ptr::null_mut(),
         avail_in: 0,
@@ -39,16 +119,191 @@ fn new_z_stream() -> z_stream {
     }
 }
 
+/// What `IndexBuilder` does with input after the last gzip member that is not
+/// another member, such as the zero padding of tape and disk images.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TrailingData {
+    /// Fail with a data error
+    #[default]
+    Error,
+    /// End the index at the last complete member, whatever follows
+    Stop,
+    /// Skip zero bytes, and only fail on other data
+    SkipZeros,
+}
+
+/// Options for building a `DeflateIndex`.
+#[derive(Debug, Clone)]
+pub struct IndexBuilder {
+    span: u64,
+    delimiter: Option<u8>,
+    record_aligned: bool,
+    mode: CompressionMode,
+    input_size: usize,
+    dictionary: Option<Vec<u8>>,
+    trailing: TrailingData,
+}
+
+impl Default for IndexBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl IndexBuilder {
+    pub fn new() -> Self {
+        Self {
+            span: SPAN,
+            delimiter: None,
+            record_aligned: false,
+            mode: CompressionMode::Auto,
+            input_size: CHUNK,
+            dictionary: None,
+            trailing: TrailingData::Error,
+        }
+    }
+
+    /// Minimum distance in uncompressed bytes between access points.
+    pub fn span(mut self, span: u64) -> Self {
+        self.span = span;
+        self
+    }
+
+    /// Count newlines and store the line number of every access point.
+    pub fn count_lines(self) -> Self {
+        self.delimiter(b'\n')
+    }
+
+    /// Count occurrences of a record delimiter instead of newlines.
+    pub fn delimiter(mut self, delimiter: u8) -> Self {
+        self.delimiter = Some(delimiter);
+        self
+    }
+
+    /// Only add an access point once a new record has started since the
+    /// previous one, so every point can begin its own record-aligned shard.
+    /// Has no effect without a delimiter.
+    pub fn record_aligned(mut self) -> Self {
+        self.record_aligned = true;
+        self
+    }
+
+    /// Bytes of compressed input read at a time. Reads of 1-4 MiB suit fast
+    /// or high latency storage better than the default of 16 KiB.
+    pub fn input_buffer_size(mut self, bytes: usize) -> Self {
+        // Room to peek at the header of the next gzip member
+        self.input_size = buffer_size(bytes).max(MEMBER_PEEK);
+        self
+    }
+
+    /// Preset dictionary of the stream. A zlib stream that declares one
+    /// (FDICT) can only be indexed with it, and its Adler-32 must match the
+    /// one in the stream header. Raw deflate data is started with it. The
+    /// window of the first access point holds the dictionary, so no point
+    /// needs it again for reading.
+    pub fn with_dictionary(mut self, dictionary: &[u8]) -> Self {
+        self.dictionary = Some(dictionary.to_vec());
+        self
+    }
+
+    /// What to do with data following the last gzip member. The compressed
+    /// offset where the indexed data ends is kept as `DeflateIndex::data_end`.
+    pub fn trailing_data(mut self, trailing: TrailingData) -> Self {
+        self.trailing = trailing;
+        self
+    }
+
+    /// Format of the data. The default, `Auto`, detects it from the header,
+    /// so only data that merely looks like zlib or gzip, such as the raw
+    /// deflate data of zip entries, needs it set.
+    pub fn mode(mut self, mode: CompressionMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    pub fn build<R: Read + Seek>(&self, reader: &mut R) -> io::Result<DeflateIndex> {
+        build(reader, self, None, &mut |_| {})
+    }
+
+    /// Builds an index of `source` and records its fingerprint, so that
+    /// reading with the index after the data has been replaced fails.
+    pub fn build_source<S: RandomAccessSource>(&self, source: S) -> io::Result<DeflateIndex> {
+        let fingerprint = source.fingerprint();
+        let mut index = self.build(&mut SourceReader::new(source))?;
+        index.fingerprint = fingerprint;
+        Ok(index)
+    }
+
+    // Like `build`, also passing all uncompressed data to `observe` in order
+    pub(crate) fn build_observed<R: Read + Seek>(
+        &self,
+        reader: &mut R,
+        observe: &mut dyn FnMut(&[u8]),
+    ) -> io::Result<DeflateIndex> {
+        build(reader, self, None, observe)
+    }
+
+    /// Builds an index of damaged or truncated data. Instead of failing at
+    /// the first error, decoding resumes at the next gzip member or flush
+    /// marker that decodes cleanly, and the skipped compressed ranges are
+    /// reported alongside the index.
+    ///
+    /// Other deflate block starts are not searched for, as they are not byte
+    /// aligned and the window before them is lost. Data after the damage in
+    /// a single member without flush points, as plain `gzip` writes, is not
+    /// recovered. `SeekableGzWriter` output resumes at every access point.
+    pub fn build_tolerant<R: Read + Seek>(&self, reader: &mut R) -> io::Result<RecoveredIndex> {
+        let mut damaged = vec![];
+        let index = build(reader, self, Some(&mut damaged), &mut |_| {})?;
+        Ok(RecoveredIndex { index, damaged })
+    }
+}
+
+/// A compressed byte range that could not be decoded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DamagedRange {
+    pub start: u64, // compressed offset where decoding failed
+    pub end: u64,   // compressed offset where decoding resumed, or the end of input
+    pub out: u64,   // uncompressed offset of the gap in the recovered data
+    pub error: String,
+}
+
+/// Result of `IndexBuilder::build_tolerant`.
+#[derive(Debug, Clone)]
+pub struct RecoveredIndex {
+    pub index: DeflateIndex,
+    pub damaged: Vec<DamagedRange>,
+}
+
 pub fn build_index<R: Read + Seek>(reader: &mut R, span: u64) -> io::Result<DeflateIndex> {
+    IndexBuilder::new().span(span).build(reader)
+}
+
+fn build<R: Read + Seek>(
+    reader: &mut R,
+    options: &IndexBuilder,
+    mut damaged: Option<&mut Vec<DamagedRange>>,
+    observe: &mut dyn FnMut(&[u8]),
+) -> io::Result<DeflateIndex> {
+    let span = options.span;
     let mut in_stream = PushbackReader::new(reader);
     let mut stream: z_stream = new_z_stream();
 
-    let mut buffer = vec![0; CHUNK];
+    let mut buffer = vec![0; options.input_size];
     let mut win = vec![0; WINSIZE]; // output sliding window
+    let mut decoded = vec![0; WINSIZE]; // inflate() output, copied into win
     let mut totin = 0u64; // total bytes read from input
     let mut totout = 0u64; // total bytes uncompressed
-    let mut mode = 0; // mode: RAW, ZLIB, or GZIP (0 => not set yet)
+    let mut mode = CompressionMode::Auto; // RAW, ZLIB, or GZIP once known
     let mut last = 0u64; // last access point uncompressed offset
+    let mut lines = 0u64; // delimiters seen in the uncompressed data
+    let mut last_lines = 0u64; // delimiters before the last access point
+    let mut at_record = true; // whether totout is the start of a record
+    let mut pending = None; // first access point still waiting for a record start
+    let mut resync_point = false; // add a window-free access point at the next header
+    let mut raw_member = false; // raw inflating the rest of a gzip member after a resync
+    let mut data_end = 0u64; // compressed offset after the last complete stream or member
+    let mut members = 0u64; // gzip members started
 
     // list of access points
     let mut index = DeflateIndex::new();
@@ -59,41 +314,50 @@ pub fn build_index<R: Read + Seek>(reader: &mut R, span: u64) -> io::Result<Defl
         loop {
             // Assure available input, at least until reaching EOF.
             if stream.avail_in == 0 {
-                stream.avail_in = fread(&mut in_stream, &mut buffer, CHUNK)? as u32;
+                stream.avail_in = fread(&mut in_stream, &mut buffer, options.input_size)? as u32;
                 totin += stream.avail_in as u64;
                 stream.next_in = buffer.as_mut_ptr();
             }
 
-            if mode == 0 {
-                // At the start of the input -- determine the type. Assume raw
-                // if it is neither zlib nor gzip. This could in theory result
-                // in a false positive for zlib, but in practice the fill bits
-                // after a stored block are always zeros, so a raw stream won't
-                // start with an 8 in the low nybble.
-                mode = match stream.avail_in {
-                    0 => CompressionMode::Raw as i32, // empty -- will fail
-                    _ if (*stream.next_in & 0xf) == 8 => CompressionMode::Zlib as i32,
-                    _ if *stream.next_in == 0x1f => CompressionMode::Gzip as i32,
-                    _ => CompressionMode::Raw as i32,
+            if mode == CompressionMode::Auto {
+                // At the start of the input -- determine the type.
+                mode = match options.mode {
+                    CompressionMode::Auto => detect_mode(&stream),
+                    mode => mode,
                 };
-
-                ret = inflateInit2(&mut stream, mode);
+                ret = inflateInit2(&mut stream, mode as i32);
+                members = u64::from(mode == CompressionMode::Gzip);
                 if ret != Z_OK {
                     return Err(io::Error::new(
                         io::ErrorKind::Other,
                         format!("inflateInit2 error: {}", zlib_error_description(ret)),
                     ));
                 }
+                if mode == CompressionMode::Zlib && stream.avail_in > 0 {
+                    // CINFO in the zlib header gives the window size the
+                    // compressor used, so points need no more history.
+                    let cinfo = (*stream.next_in >> 4) as u32;
+                    if cinfo <= 7 {
+                        index.window_size = 1 << (cinfo + 8);
+                    }
+                }
+                if let (Some(dictionary), true) =
+                    (&options.dictionary, mode == CompressionMode::Raw)
+                {
+                    set_dictionary(&mut stream, dictionary, &mut win)?;
+                }
             }
 
             // Assure available output. This rotates the output through, for use as
-            // a sliding window on the uncompressed data.
+            // a sliding window on the uncompressed data. inflate() may write to
+            // all of the output space it is given, so it decodes into a buffer at
+            // the same positions and the output is copied into win.
             if stream.avail_out == 0 {
                 stream.avail_out = WINSIZE as u32;
-                stream.next_out = win.as_mut_ptr();
+                stream.next_out = decoded.as_mut_ptr();
             }
 
-            if mode == CompressionMode::Raw as i32 && index.list.is_empty() {
+            if mode == CompressionMode::Raw && index.list.is_empty() {
                 // We skip the inflate() call at the start of raw deflate data in
                 // order generate an access point there. Set data_type to imitate
                 // the end of a header.
@@ -103,9 +367,45 @@ pub fn build_index<R: Read + Seek>(reader: &mut R, span: u64) -> io::Result<Defl
                 let before = stream.avail_out;
                 ret = inflate(&mut stream, Z_BLOCK);
                 totout += (before - stream.avail_out) as u64;
+                if let (Some(dictionary), Z_NEED_DICT) = (&options.dictionary, ret) {
+                    // The zlib header asks for the dictionary before any data.
+                    // Moving on to the first block consumes nothing, which
+                    // inflate reports as a buffer error.
+                    set_dictionary(&mut stream, dictionary, &mut win)?;
+                    ret = match inflate(&mut stream, Z_BLOCK) {
+                        Z_BUF_ERROR => Z_OK,
+                        ret => ret,
+                    };
+                }
+
+                let end = WINSIZE - stream.avail_out as usize;
+                let start = WINSIZE - before as usize;
+                win[start..end].copy_from_slice(&decoded[start..end]);
+                if end > start {
+                    observe(&win[start..end]);
+                }
+                if let (Some(delimiter), true) = (options.delimiter, end > start) {
+                    let output = &win[start..end];
+                    lines += count_delimiters(output, delimiter);
+
+                    // Resolve the record start of points added before this output
+                    if let Some(first) = pending {
+                        if let Some(i) = output.iter().position(|&b| b == delimiter) {
+                            let record = totout - output.len() as u64 + i as u64 + 1;
+                            for point in &mut index.list[first..] {
+                                point.record = record;
+                            }
+                            pending = None;
+                        }
+                    }
+                    at_record = output[output.len() - 1] == delimiter;
+                }
             }
 
-            if (stream.data_type & 0xc0) == 0x80 && (index.list.is_empty() || totout - last >= span)
+            let aligned = options.record_aligned && options.delimiter.is_some();
+            let new_record = !aligned || lines > last_lines;
+            if (stream.data_type & 0xc0) == 0x80
+                && (index.list.is_empty() || resync_point || (totout - last >= span && new_record))
             {
                 /*  if at end of block, consider adding an index entry (note that if
                     data_type indicates an end-of-block, then all of the
@@ -117,24 +417,108 @@ pub fn build_index<R: Read + Seek>(reader: &mut R, span: u64) -> io::Result<Defl
                     access point after the last block by checking bit 6 of data_type
                 */
 
-                index.add_point(
+                let point = index.add_point(
                     stream.data_type as u32 & 7,
                     totin - stream.avail_in as u64,
                     totout,
                     stream.avail_out as usize,
                     &win,
                 );
+                point.lines = lines;
+                point.record = totout;
+                if resync_point {
+                    point.window = vec![];
+                    point.resync = true;
+                    resync_point = false;
+                }
+                if !at_record && options.delimiter.is_some() {
+                    pending = pending.or(Some(index.list.len() - 1));
+                }
                 last = totout;
+                last_lines = lines;
+            }
+
+            if ret == Z_STREAM_END && raw_member {
+                // Skip the trailer of the member that was resumed in raw mode
+                fill_ahead(&mut stream, &mut buffer, &mut in_stream, &mut totin, 8)?;
+                let drop = std::cmp::min(8, stream.avail_in);
+                stream.avail_in -= drop;
+                stream.next_in = stream.next_in.add(drop as usize);
+                raw_member = false;
+            }
+            if ret == Z_STREAM_END {
+                data_end = totin - stream.avail_in as u64;
             }
 
             if ret == Z_STREAM_END
-                && mode == CompressionMode::Gzip as i32
+                && mode == CompressionMode::Gzip
                 && (stream.avail_in != 0 || !is_eof(&mut in_stream)?)
             {
-                // There is more input after the end of a gzip member. Reset the
-                // inflate state to read another gzip member. On success, this will
-                // set ret to Z_OK to continue decompressing.
-                ret = inflateReset2(&mut stream, CompressionMode::Gzip as i32);
+                // There is more input after the end of a gzip member. Stop at
+                // an embedded index, otherwise reset the inflate state to read
+                // another gzip member. On success, this will set ret to Z_OK to
+                // continue decompressing. Other data is handled as configured.
+                if options.trailing == TrailingData::SkipZeros {
+                    skip_zeros(&mut stream, &mut buffer, &mut in_stream, &mut totin)?;
+                }
+                fill_ahead(
+                    &mut stream,
+                    &mut buffer,
+                    &mut in_stream,
+                    &mut totin,
+                    MEMBER_PEEK,
+                )?;
+                let next = std::slice::from_raw_parts(stream.next_in, stream.avail_in as usize);
+                let stop = next.is_empty()
+                    || is_index_member(next)
+                    || (options.trailing == TrailingData::Stop && !is_gzip_header(next));
+                if !stop {
+                    ret = inflateReset2(&mut stream, CompressionMode::Gzip as i32);
+                    members += 1;
+                }
+            }
+
+            if let (Some(damaged), false) = (damaged.as_mut(), ret == Z_OK || ret == Z_STREAM_END) {
+                // Record the damage and look for a later position where
+                // decoding can restart from scratch.
+                let start = totin - stream.avail_in as u64;
+                let gzip = mode == CompressionMode::Gzip;
+                let found = resync(&mut in_stream, start + 1, gzip)?;
+                damaged.push(DamagedRange {
+                    start,
+                    end: match found {
+                        Some((pos, _)) => pos,
+                        None => in_stream.seek(SeekFrom::End(0))?,
+                    },
+                    out: totout,
+                    error: zlib_error_description(ret).to_string(),
+                });
+
+                if let Some((pos, member)) = found {
+                    in_stream.seek(SeekFrom::Start(pos))?;
+                    totin = pos;
+                    stream.avail_in = 0;
+                    if member {
+                        ret = inflateReset2(&mut stream, CompressionMode::Gzip as i32);
+                        resync_point = true;
+                        members += 1;
+                    } else {
+                        // A flush marker leaves the input byte aligned with
+                        // no history, so the access point needs no window.
+                        ret = inflateReset2(&mut stream, CompressionMode::Raw as i32);
+                        raw_member = gzip;
+                        let point = index.add_point(0, pos, totout, 0, &win);
+                        point.window = vec![];
+                        point.resync = true;
+                        point.lines = lines;
+                        point.record = totout;
+                        if !at_record && options.delimiter.is_some() {
+                            pending = pending.or(Some(index.list.len() - 1));
+                        }
+                        last = totout;
+                        last_lines = lines;
+                    }
+                }
             }
 
             // Keep going until Z_STREAM_END or error. If the compressed data ends
@@ -145,7 +529,7 @@ pub fn build_index<R: Read + Seek>(reader: &mut R, span: u64) -> io::Result<Defl
         }
 
         inflateEnd(&mut stream);
-        if ret != Z_STREAM_END {
+        if ret != Z_STREAM_END && damaged.is_none() {
             // An error was encountered. Discard the index and return a negative
             // error code
             return Err(io::Error::new(
@@ -154,175 +538,524 @@ pub fn build_index<R: Read + Seek>(reader: &mut R, span: u64) -> io::Result<Defl
             ));
         }
 
+        if ret != Z_STREAM_END {
+            // Damaged data, decoded up to here
+            data_end = totin - stream.avail_in as u64;
+        }
+
         index.mode = mode;
         index.length = totout;
+        index.data_end = data_end;
+        index.members = members;
+        index.zero_padding = options.trailing == TrailingData::SkipZeros;
+        index.delimiter = options.delimiter;
+        index.lines = lines;
+
+        // No record starts after these points
+        if let Some(first) = pending {
+            for point in &mut index.list[first..] {
+                point.record = totout;
+            }
+        }
     }
 
     Ok(index)
 }
 
-pub fn extract_data<R: Read + Seek>(
-    reader: &mut PushbackReader<R>,
-    index: &DeflateIndex,
-    offset: u64,
-    buffer: &mut [u8],
-) -> io::Result<usize> {
-    // Do a quick check on the index
-    if index.list.is_empty() || index.list[0].out != 0 {
-        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid index"));
-    }
+// Finds the first position at or after `from` where decoding can restart
+// without history: a gzip member header, or the end of a flush marker (the
+// empty stored block 00 00 ff ff). Returns the position and whether it is a
+// gzip member.
+fn resync<R: Read + Seek>(
+    reader: &mut R,
+    from: u64,
+    gzip: bool,
+) -> io::Result<Option<(u64, bool)>> {
+    let mut chunk = vec![0; CHUNK + 3];
+    let mut base = from;
+    loop {
+        reader.seek(SeekFrom::Start(base))?;
+        let n = fread(reader, &mut chunk, CHUNK + 3)?;
+        let scan = if n == chunk.len() {
+            CHUNK
+        } else {
+            n.saturating_sub(2)
+        };
 
-    // If nothing to extract, return zero bytes extracted
-    if offset >= index.length {
-        return Ok(0);
-    }
+        for i in 0..scan {
+            let candidate = if gzip && chunk[i..i + 3] == [0x1f, 0x8b, 8] {
+                Some((base + i as u64, true))
+            } else if i + 4 <= n && chunk[i..i + 4] == [0, 0, 0xff, 0xff] {
+                Some((base + i as u64 + 4, false))
+            } else {
+                None
+            };
 
-    // Find the access point closest to but not after offset
-    let mut lo = -1;
-    let mut hi = index.list.len() as isize;
-    while hi - lo > 1 {
-        let mid = (lo + hi) / 2;
-        if offset < index.list[mid as usize].out {
-            hi = mid;
-        } else {
-            lo = mid;
+            if let Some((pos, member)) = candidate {
+                if trial_decode(reader, pos, member)? {
+                    return Ok(Some((pos, member)));
+                }
+            }
         }
+
+        if n < chunk.len() {
+            return Ok(None);
+        }
+        base += CHUNK as u64;
     }
+}
+
+// Checks that decoding from `pos` produces a good amount of data, or
+// reaches the end of the stream, without errors.
+fn trial_decode<R: Read + Seek>(reader: &mut R, pos: u64, member: bool) -> io::Result<bool> {
+    const TRIAL: u64 = 65536;
 
-    let point = &index.list[lo as usize];
+    reader.seek(SeekFrom::Start(pos))?;
     let mut stream: z_stream = new_z_stream();
+    let mut input = vec![0; CHUNK];
+    let mut output = vec![0; WINSIZE];
+    let mut produced = 0u64;
 
     unsafe {
-        let seek_offset = point.inn - (if point.bits != 0 { 1 } else { 0 }) as u64;
-        reader.seek(SeekFrom::Start(seek_offset))?;
-
-        let ch = if point.bits != 0 {
-            let mut byte = [0u8];
-            reader.read_exact(&mut byte)?;
-            byte[0] as i32
+        let mode = if member {
+            CompressionMode::Gzip
         } else {
-            0
+            CompressionMode::Raw
         };
+        let mut ret = inflateInit2(&mut stream, mode as i32);
+        while ret == Z_OK && produced < TRIAL {
+            if stream.avail_in == 0 {
+                stream.avail_in = fread(reader, &mut input, CHUNK)? as u32;
+                stream.next_in = input.as_mut_ptr();
+            }
+            stream.avail_out = WINSIZE as u32;
+            stream.next_out = output.as_mut_ptr();
+            ret = inflate(&mut stream, Z_NO_FLUSH);
+            produced += (WINSIZE - stream.avail_out as usize) as u64;
+        }
+        inflateEnd(&mut stream);
 
-        let ret = inflateInit2(&mut stream, CompressionMode::Raw as i32);
+        // Truncated input still counts if some data came out of it
+        Ok(ret == Z_OK || ret == Z_STREAM_END || (ret == Z_BUF_ERROR && produced > 0))
+    }
+}
 
-        if ret != Z_OK {
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                format!("inflateInit2_ error: {}", ret),
-            ));
+/// Streaming decompressor that resumes inflation at an access point and
+/// continues across gzip members. Decoding uses nothing but the point, so
+/// it also tests that the point is right.
+pub struct PointDecoder<S: RandomAccessSource> {
+    reader: SourceReader<S>,
+    stream: Box<z_stream>, // boxed because zlib keeps a pointer back to it
+    input: Vec<u8>,
+    gzip: bool,
+    end: u64, // compressed offset where the indexed data ends
+    zero_padding: bool,
+    done: bool,
+}
+
+impl<S: RandomAccessSource> PointDecoder<S> {
+    pub fn new(source: S, index: &DeflateIndex, point: &Point) -> io::Result<Self> {
+        Self::with_input_size(source, index, point, CHUNK)
+    }
+
+    // Like `new`, reading `input_size` compressed bytes at a time
+    pub(crate) fn with_input_size(
+        source: S,
+        index: &DeflateIndex,
+        point: &Point,
+        input_size: usize,
+    ) -> io::Result<Self> {
+        check_fingerprint(&source, index)?;
+        let mut decoder = Self {
+            reader: SourceReader::new(source),
+            stream: Box::new(new_z_stream()),
+            input: vec![0; input_size],
+            gzip: index.mode == CompressionMode::Gzip,
+            // Without a known end, decode every member up to the end of input
+            end: if index.data_end == 0 {
+                u64::MAX
+            } else {
+                index.data_end
+            },
+            zero_padding: index.zero_padding,
+            done: false,
+        };
+
+        unsafe {
+            let ret = inflateInit2(&mut *decoder.stream, CompressionMode::Raw as i32);
+            if ret != Z_OK {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("inflateInit2_ error: {}", ret),
+                ));
+            }
+
+            let seek_offset = point.inn - (if point.bits != 0 { 1 } else { 0 }) as u64;
+            decoder.reader.seek(SeekFrom::Start(seek_offset))?;
+
+            if point.bits != 0 {
+                let mut byte = [0u8];
+                decoder.reader.read_exact(&mut byte)?;
+                let ch = byte[0] as i32;
+                inflatePrime(
+                    &mut *decoder.stream,
+                    point.bits as i32,
+                    ch >> (8 - point.bits as i32),
+                );
+            }
+            if !point.window.is_empty() {
+                inflateSetDictionary(
+                    &mut *decoder.stream,
+                    point.window.as_ptr(),
+                    point.window.len() as u32,
+                );
+            }
         }
 
-        if point.bits != 0 {
-            inflatePrime(
-                &mut stream,
-                point.bits as i32,
-                ch >> (8 - point.bits as i32),
-            );
-            inflateSetDictionary(&mut stream, point.window.as_ptr(), WINSIZE as u32);
+        Ok(decoder)
+    }
+
+    // Inflates into `buf`, stopping at the next deflate block boundary.
+    // Returns the bytes produced and, when stopped at a boundary, the number
+    // of bits of the previous byte the next block starts with. The start of
+    // a later gzip member counts as a boundary.
+    pub(crate) fn read_block(&mut self, buf: &mut [u8]) -> io::Result<(usize, Option<u32>)> {
+        if self.done || buf.is_empty() {
+            return Ok((0, None));
         }
+        unsafe {
+            self.fill_input()?;
+            self.stream.avail_out = std::cmp::min(buf.len(), u32::MAX as usize) as u32;
+            self.stream.next_out = buf.as_mut_ptr();
 
-        // Skip uncompressed bytes until offset reached, then satisfy request.
-        let mut input_buffer = vec![0; CHUNK];
-        let mut discard_buffer = vec![0; WINSIZE];
+            let before = self.stream.avail_out;
+            let ret = inflate(&mut *self.stream, Z_BLOCK);
+            let got = (before - self.stream.avail_out) as usize;
 
-        let mut offset = offset - point.out; // number of bytes to skip to get to offset
-        let mut left = buffer.len(); // number of bytes left to read after offset
+            match ret {
+                Z_OK if self.stream.data_type & 0xc0 == 0x80 => {
+                    Ok((got, Some(self.stream.data_type as u32 & 7)))
+                }
+                Z_OK => Ok((got, None)),
+                Z_STREAM_END if self.gzip => {
+                    self.done = !self.next_member()?;
+                    Ok((got, if self.done { None } else { Some(0) }))
+                }
+                Z_STREAM_END => {
+                    self.done = true;
+                    Ok((got, None))
+                }
+                _ => Err(inflate_error(ret)),
+            }
+        }
+    }
+
+    // Compressed offset of the next byte to inflate
+    pub(crate) fn position(&self) -> u64 {
+        self.reader.position() - self.stream.avail_in as u64
+    }
+
+    // Assure available input, at least until reaching EOF.
+    fn fill_input(&mut self) -> io::Result<()> {
+        if self.stream.avail_in == 0 {
+            let len = self.input.len();
+            self.stream.avail_in = fread(&mut self.reader, &mut self.input, len)? as u32;
+            self.stream.next_in = self.input.as_mut_ptr();
+        }
+        Ok(())
+    }
 
+    // At the end of a gzip member, skip its trailer and the next member's
+    // header so raw inflation can resume there. Returns false at the end of
+    // the input.
+    unsafe fn next_member(&mut self) -> io::Result<bool> {
+        // Discard the gzip trailer
+        let mut drop = 8;
+        if self.stream.avail_in >= drop as u32 {
+            self.stream.avail_in -= drop as u32;
+            self.stream.next_in = self.stream.next_in.add(drop);
+        } else {
+            drop -= self.stream.avail_in as usize;
+            self.stream.avail_in = 0;
+            let mut discard = vec![0; drop];
+            self.reader.read_exact(&mut discard)?;
+        }
+
+        // Stop at the end of the indexed data, before any trailing garbage
+        if self.position() >= self.end {
+            return Ok(false);
+        }
+
+        // Skip zero padding, which an index built with TrailingData::SkipZeros
+        // allows between members
         loop {
-            if offset != 0 {
-                // Discard up to offset uncompressed bytes
-                stream.avail_out = (if offset < WINSIZE as u64 {
-                    offset
-                } else {
-                    WINSIZE as u64
-                }) as u32;
-                stream.next_out = discard_buffer.as_mut_ptr();
-            } else {
-                // Uncompress up to left bytes into buf
-                stream.avail_out = (if left < u32::MAX as usize {
-                    left
-                } else {
-                    u32::MAX as usize
-                }) as u32;
-                stream.next_out = buffer.as_mut_ptr().add(buffer.len() - left);
+            self.fill_input()?;
+            while self.zero_padding && self.stream.avail_in > 0 && *self.stream.next_in == 0 {
+                self.stream.next_in = self.stream.next_in.add(1);
+                self.stream.avail_in -= 1;
+            }
+            if self.stream.avail_in > 0 {
+                break;
             }
+            if self.reader.is_eof()? {
+                return Ok(false);
+            }
+        }
 
-            // Uncompress, setting got to the number of bytes uncompressed
-            if stream.avail_in == 0 {
-                // Assure available input
-                stream.avail_in = fread(reader, &mut input_buffer, CHUNK)? as u32;
-                stream.next_in = input_buffer.as_mut_ptr();
+        // There's more after the gzip trailer. Use inflate to skip the gzip
+        // header and resume the raw inflate there.
+        let mut discard_buffer = vec![0u8; WINSIZE];
+        inflateReset2(&mut *self.stream, CompressionMode::Gzip as i32);
+        loop {
+            self.fill_input()?;
+            self.stream.avail_out = WINSIZE as u32;
+            self.stream.next_out = discard_buffer.as_mut_ptr();
+            let ret = inflate(&mut *self.stream, Z_BLOCK);
+            if ret != Z_OK {
+                return Err(inflate_error(ret));
+            }
+            if (self.stream.data_type & 0x80) != 0 {
+                break;
             }
+        }
+        inflateReset2(&mut *self.stream, CompressionMode::Raw as i32);
+        Ok(true)
+    }
+}
 
-            let before = stream.avail_out;
-            let ret = inflate(&mut stream, Z_NO_FLUSH);
-            let got = before - stream.avail_out;
+impl<S: RandomAccessSource> Read for PointDecoder<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut got = 0;
+        unsafe {
+            // Continue until some data was produced or the deflate data has
+            // ended.
+            while got == 0 && !self.done && !buf.is_empty() {
+                self.fill_input()?;
+                self.stream.avail_out = std::cmp::min(buf.len(), u32::MAX as usize) as u32;
+                self.stream.next_out = buf.as_mut_ptr();
 
-            if offset != 0 {
-                offset -= got as u64;
-            } else {
-                left -= got as usize;
-            }
+                let before = self.stream.avail_out;
+                let ret = inflate(&mut *self.stream, Z_NO_FLUSH);
+                got = (before - self.stream.avail_out) as usize;
 
-            // If we're at the end of a gzip member and there's more to read,
-            // continue to the next gzip member.
-            if ret == Z_STREAM_END && index.mode == CompressionMode::Gzip as i32 {
-                // Discard the gzip trailer
-                let mut drop = 8;
-                if stream.avail_in >= drop as u32 {
-                    stream.avail_in -= drop as u32;
-                    stream.next_in = stream.next_in.add(drop);
-                } else {
-                    drop -= stream.avail_in as usize;
-                    stream.avail_in = 0;
-                    let mut discard = vec![0; drop];
-                    reader.read_exact(&mut discard)?;
+                match ret {
+                    Z_OK => {}
+                    // If we're at the end of a gzip member and there's more
+                    // to read, continue to the next gzip member.
+                    Z_STREAM_END if self.gzip => self.done = !self.next_member()?,
+                    Z_STREAM_END => self.done = true,
+                    _ => return Err(inflate_error(ret)),
                 }
+            }
+        }
+        Ok(got)
+    }
+}
 
-                if stream.avail_in != 0 || !is_eof(reader)? {
-                    // There's more after the gzip trailer. Use inflate to skip the
-                    // gzip header and resume the raw inflate there.
-                    inflateReset2(&mut stream, CompressionMode::Gzip as i32);
-                    loop {
-                        if stream.avail_in == 0 {
-                            stream.avail_in = fread(reader, &mut input_buffer, CHUNK)? as u32;
-                            stream.next_in = input_buffer.as_mut_ptr();
-                        }
-                        stream.avail_out = WINSIZE as u32;
-                        stream.next_out = discard_buffer.as_mut_ptr();
-                        let ret = inflate(&mut stream, Z_BLOCK);
-                        if ret != Z_OK || (stream.data_type & 0x80) != 0 {
-                            break;
-                        }
-                    }
-                    if ret != Z_OK {
-                        break;
+impl<S: RandomAccessSource> Drop for PointDecoder<S> {
+    fn drop(&mut self) {
+        unsafe {
+            inflateEnd(&mut *self.stream);
+        }
+    }
+}
+
+pub fn extract_data<S: RandomAccessSource + ?Sized>(
+    source: &S,
+    index: &DeflateIndex,
+    offset: u64,
+    buffer: &mut [u8],
+) -> io::Result<usize> {
+    extract_with_input_size(source, index, offset, buffer, CHUNK)
+}
+
+// Like `extract_data`, reading `input_size` compressed bytes at a time
+pub(crate) fn extract_with_input_size<S: RandomAccessSource + ?Sized>(
+    source: &S,
+    index: &DeflateIndex,
+    offset: u64,
+    buffer: &mut [u8],
+    input_size: usize,
+) -> io::Result<usize> {
+    // Do a quick check on the index
+    if index.list.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid index"));
+    }
+
+    // If nothing to extract, return zero bytes extracted
+    if offset >= index.length {
+        return Ok(0);
+    }
+
+    // Find the access point closest to but not after offset. Only a slice
+    // of an index may have none.
+    let point = &index.list[index.locate(offset).ok_or_else(before_first_point)?];
+    let mut decoder = PointDecoder::with_input_size(source, index, point, input_size)?;
+
+    // Skip uncompressed bytes until offset reached, then satisfy request.
+    let skip = offset - point.out;
+    if skip != 0 {
+        let copied = io::copy(&mut (&mut decoder).take(skip), &mut io::sink())?;
+        if copied < skip {
+            return Ok(0);
+        }
+    }
+
+    // Return the number of uncompressed bytes read into buf, or the error.
+    // Stop at the indexed length rather than decoding trailing members.
+    let want = std::cmp::min(buffer.len() as u64, index.length - offset) as usize;
+    let mut total = 0;
+    while total < want {
+        match decoder.read(&mut buffer[total..want])? {
+            0 => break,
+            n => total += n,
+        }
+    }
+    Ok(total)
+}
+
+// Starts inflating with a preset dictionary, before any output. The
+// dictionary is put at the end of the sliding window, as if it had just been
+// decoded, so that access points within its reach keep it in their windows.
+unsafe fn set_dictionary(
+    stream: &mut z_stream,
+    dictionary: &[u8],
+    win: &mut [u8],
+) -> io::Result<()> {
+    let ret = inflateSetDictionary(stream, dictionary.as_ptr(), dictionary.len() as u32);
+    match ret {
+        Z_OK => {
+            // Only the last WINSIZE bytes can be referred to
+            let tail = &dictionary[dictionary.len().saturating_sub(WINSIZE)..];
+            win[WINSIZE - tail.len()..].copy_from_slice(tail);
+            Ok(())
+        }
+        Z_DATA_ERROR => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Dictionary does not match the Adler-32 in the zlib header",
+        )),
+        _ => Err(inflate_error(ret)),
+    }
+}
+
+// Keeps a configured buffer size usable: nonzero, and small enough for the
+// 32 bit counts of a z_stream.
+pub(crate) fn buffer_size(bytes: usize) -> usize {
+    bytes.clamp(1, u32::MAX as usize)
+}
+
+/// Returns a copy of `index` with access points added every `span`
+/// uncompressed bytes up to `end`, decoding only from the access point before
+/// `start`. Lets the density of an index be raised for a region without
+/// building it again; `DeflateIndex::thin` lowers it.
+pub fn densify<S: RandomAccessSource + ?Sized>(
+    source: &S,
+    index: &DeflateIndex,
+    start: u64,
+    end: u64,
+    span: u64,
+) -> io::Result<DeflateIndex> {
+    let end = std::cmp::min(end, index.length);
+    let point = &index.list[index.locate(start).ok_or_else(before_first_point)?];
+    let mut decoder = PointDecoder::new(source, index, point)?;
+
+    // The output rotates through win as in `build`, after the point's window
+    let mut win = vec![0; WINSIZE];
+    win[WINSIZE - point.window.len()..].copy_from_slice(&point.window);
+    // inflate() may write to all of the output space it is given, so decode
+    // apart from the window and copy the output in
+    let mut chunk = vec![0; WINSIZE];
+    let mut pos = 0; // where the next output goes in win
+    let mut out = point.out;
+    let mut last = point.out; // last access point, added or existing
+    let mut lines = point.lines;
+    let mut at_record = point.record == point.out;
+    let mut pending = None; // first added point still waiting for a record start
+    let mut added = index.with_points(vec![]);
+
+    // Past the end, decode only as far as needed to find record starts
+    while out < end || pending.is_some() {
+        if pos == WINSIZE {
+            pos = 0;
+        }
+        let (got, boundary) = decoder.read_block(&mut chunk[..WINSIZE - pos])?;
+        win[pos..pos + got].copy_from_slice(&chunk[..got]);
+        if got == 0 && boundary.is_none() && decoder.done {
+            break;
+        }
+
+        let output = &win[pos..pos + got];
+        if let (Some(delimiter), true) = (index.delimiter, got > 0) {
+            lines += count_delimiters(output, delimiter);
+            if let Some(first) = pending {
+                if let Some(i) = output.iter().position(|&b| b == delimiter) {
+                    for point in &mut added.list[first..] {
+                        point.record = out + i as u64 + 1;
                     }
-                    inflateReset2(&mut stream, CompressionMode::Raw as i32);
+                    pending = None;
                 }
             }
+            at_record = output[got - 1] == delimiter;
+        }
+        pos += got;
+        out += got as u64;
 
-            // Continue until we have the requested data, the deflate data has
-            // ended, or an error is encountered.
-            if !(ret == Z_OK && left != 0) {
-                break;
+        // An existing point passed with no delimiter since the added ones
+        // has their record start
+        let existing = &index.list[index.locate(out).unwrap()];
+        last = std::cmp::max(last, existing.out);
+        if let Some(first) = pending {
+            if existing.out > added.list[first].out {
+                for point in &mut added.list[first..] {
+                    point.record = existing.record;
+                }
+                pending = None;
             }
         }
-        inflateEnd(&mut stream);
 
-        // Return the number of uncompressed bytes read into buf, or the error.
-        match ret {
-            Z_OK | Z_STREAM_END => Ok(buffer.len() - left),
-            _ => Err(io::Error::new(
-                io::ErrorKind::Other,
-                format!("inflate error: {}", ret),
-            )),
+        if let (Some(bits), true) = (boundary, out < end && out - last >= span) {
+            let position = decoder.position();
+            let point = added.add_point(bits, position, out, WINSIZE - pos, &win);
+            point.lines = lines;
+            point.record = out;
+            if !at_record && index.delimiter.is_some() {
+                pending = pending.or(Some(added.list.len() - 1));
+            }
+            last = out;
+        }
+    }
+
+    // No record starts after these points
+    if let Some(first) = pending {
+        for point in &mut added.list[first..] {
+            point.record = index.length;
         }
     }
+    index.merge(&added)
 }
 
-fn is_eof<R: Read + Seek>(reader: &mut PushbackReader<R>) -> io::Result<bool> {
+// Fails if the source no longer holds the data the index was built from
+fn check_fingerprint<S: RandomAccessSource>(source: &S, index: &DeflateIndex) -> io::Result<()> {
+    match (&index.fingerprint, source.fingerprint()) {
+        (Some(expected), Some(actual)) if *expected != actual => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "Source changed since it was indexed: fingerprint {} instead of {}",
+                actual, expected
+            ),
+        )),
+        _ => Ok(()),
+    }
+}
+
+pub(crate) fn before_first_point() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "Offset before the first access point",
+    )
+}
+
+pub(crate) fn is_eof<R: Read + Seek>(reader: &mut PushbackReader<R>) -> io::Result<bool> {
     let mut buf = [0; 1];
     match reader.read(&mut buf) {
         Ok(0) => Ok(true), // EOF reached
@@ -335,7 +1068,15 @@ fn is_eof<R: Read + Seek>(reader: &mut PushbackReader<R>) -> io::Result<bool> {
     }
 }
 
-fn zlib_error_description(error_code: i32) -> &'static str {
+pub(crate) fn count_delimiters(data: &[u8], delimiter: u8) -> u64 {
+    data.iter().filter(|&&b| b == delimiter).count() as u64
+}
+
+fn inflate_error(ret: i32) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, format!("inflate error: {}", ret))
+}
+
+pub(crate) fn zlib_error_description(error_code: i32) -> &'static str {
     match error_code {
         Z_OK => "No error",
         Z_STREAM_END => "End of stream",